@@ -0,0 +1,404 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use axum::extract::multipart::MultipartError;
+use axum::extract::rejection::{JsonRejection, PathRejection};
+use axum::extract::{Multipart, Path};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Json;
+use axum::Router;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::pack::{NotEnoughImages, PackReport};
+use crate::session::{Player, Session, SessionRejection};
+use crate::utils::{deserialize_bigint, serialize_bigint, SyncMutex};
+use crate::{
+    is_server_error, start_game, AppState, GameState, GuessParams, InvalidSettings, NewGame,
+    PackNotFound, PlayerState, Scoring, MAX_BLOB_STORE_SIZE,
+};
+
+/// The json api, served under `/api/v1`.
+///
+/// Requests are authenticated with the player's session token, sent as
+/// `Authorization: Bearer <token>`. Ids are sent as strings, since they don't
+/// fit in a javascript number. Errors are sent as
+/// `{ "error": "..." }`.
+pub fn router(app: Arc<SyncMutex<AppState>>) -> Router {
+    Router::new()
+        .route("/games", {
+            let app = app.clone();
+            post(
+                |session: Result<Session, SessionRejection>, multipart: Multipart| async move {
+                    create_game(app, session.ok(), multipart).await
+                },
+            )
+        })
+        .route(
+            "/games/{game_id}",
+            get(|player: Result<Player, SessionRejection>| async move {
+                let Player { game_id, game, id } = player?;
+                Ok::<_, ApiError>(Json(game.peek(|g| GameView::new(game_id, g, id))))
+            }),
+        )
+        .route("/games/{game_id}/claim", {
+            let app = app.clone();
+            post(
+                |path: Result<Path<u64>, PathRejection>,
+                 body: Result<Json<ClaimParams>, JsonRejection>| async move {
+                    let game = find_game(&app, path)?;
+                    let Json(ClaimParams { invite }) =
+                        body.map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.body_text()))?;
+                    let (seat, uid) = game.mutate(|g| g.claim_invite(invite)).ok_or_else(|| {
+                        ApiError::new(StatusCode::FORBIDDEN, "that invite is not valid")
+                    })?;
+                    Ok::<_, ApiError>(Json(json!({
+                        "seat": seat,
+                        "token": app.peek(|a| a.session_key.sign(uid)),
+                    })))
+                },
+            )
+        })
+        .route(
+            "/games/{game_id}/invites/{seat}",
+            post(
+                |player: Result<Player, SessionRejection>,
+                 path: Result<Path<(u64, usize)>, PathRejection>| async move {
+                    let Player { game, id: uid, .. } = player?;
+                    let Path((_, seat)) =
+                        path.map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.body_text()))?;
+                    let invite = game.mutate(|g| {
+                        if !g.is_host(uid) {
+                            return Err(ApiError::new(
+                                StatusCode::FORBIDDEN,
+                                "only the host can reissue invites",
+                            ));
+                        }
+                        g.reissue_invite(seat)
+                            .map_err(|e| ApiError::new(StatusCode::CONFLICT, e))
+                    })?;
+                    Ok::<_, ApiError>(Json(InviteView { seat, invite }))
+                },
+            ),
+        )
+        .route(
+            "/games/{game_id}/guess",
+            post(
+                |player: Result<Player, SessionRejection>,
+                 body: Result<Json<GuessParams>, JsonRejection>| async move {
+                    let Player { game, id: uid, .. } = player?;
+                    let Json(GuessParams { row, col }) =
+                        body.map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.body_text()))?;
+                    let correct = game.mutate(|g| {
+                        let char_idx = g.char_idx(row, col).ok_or_else(|| {
+                            ApiError::new(StatusCode::BAD_REQUEST, "tile is off the board")
+                        })?;
+                        g.guess(uid, char_idx)
+                            .map_err(|e| ApiError::new(StatusCode::CONFLICT, e))
+                    })?;
+                    Ok::<_, ApiError>(Json(json!({ "correct": correct })))
+                },
+            ),
+        )
+        .route(
+            "/games/{game_id}/results",
+            get(|player: Result<Player, SessionRejection>| async move {
+                let Player { game, .. } = player?;
+                let results = game.peek(ResultsView::new).ok_or_else(|| {
+                    ApiError::new(StatusCode::CONFLICT, "the game is not over yet")
+                })?;
+                Ok::<_, ApiError>(Json(results))
+            }),
+        )
+        .route(
+            "/games/{game_id}/log",
+            get(|player: Result<Player, SessionRejection>| async move {
+                let Player { game, .. } = player?;
+                let log = game
+                    .peek(|g| g.outcome.map(|_| g.log.clone()))
+                    .ok_or_else(|| {
+                        ApiError::new(StatusCode::CONFLICT, "the game is not over yet")
+                    })?;
+                Ok::<_, ApiError>(Json(log))
+            }),
+        )
+        .fallback(|| async { ApiError::new(StatusCode::NOT_FOUND, "no such endpoint") })
+}
+
+async fn create_game(
+    app: Arc<SyncMutex<AppState>>,
+    session: Option<Session>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    if app.peek(|g| g.blobs.referenced_size()) >= MAX_BLOB_STORE_SIZE {
+        return Err(ApiError::new(
+            StatusCode::INSUFFICIENT_STORAGE,
+            "the server is out of room for character packs",
+        ));
+    }
+    let mut settings = NewGame::from_multipart(&mut multipart).await.map_err(|e| {
+        match e.downcast_ref::<MultipartError>().map(|e| e.status()) {
+            Some(StatusCode::PAYLOAD_TOO_LARGE) => {
+                ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "the upload is too large")
+            }
+            _ => ApiError::new(StatusCode::BAD_REQUEST, e),
+        }
+    })?;
+    let (characters, report) = settings.characters(&app).await.map_err(|e| {
+        if is_server_error(&e) {
+            eprintln!("{e}");
+            eprintln!("{e:?}");
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "couldn't load the pack");
+        }
+        let status = if e.is::<InvalidSettings>() {
+            StatusCode::BAD_REQUEST
+        } else if e.is::<PackNotFound>() {
+            StatusCode::NOT_FOUND
+        } else {
+            StatusCode::UNPROCESSABLE_ENTITY
+        };
+        let report = e
+            .downcast_ref::<NotEnoughImages>()
+            .map(|NotEnoughImages(report)| report.clone());
+        ApiError::new(status, e).with_report(report)
+    })?;
+    let host = session.map(|Session(uid)| uid);
+    let (game_id, host_id) = start_game(&app, settings, characters, host);
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({
+            "game_id": game_id.to_string(),
+            "token": app.peek(|a| a.session_key.sign(host_id)),
+            "url": format!("/game/{game_id}/"),
+            "report": report,
+            "invites": app
+                .peek(|a| a.games.get(&game_id).and_then(|g| g.get()))
+                .map(|game| game.peek(InviteView::all))
+                .unwrap_or_default(),
+        })),
+    ))
+}
+
+fn find_game(
+    app: &SyncMutex<AppState>,
+    path: Result<Path<u64>, PathRejection>,
+) -> Result<Arc<SyncMutex<GameState>>, ApiError> {
+    let Path(game_id) = path.map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.body_text()))?;
+    app.peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "game not found"))
+}
+
+#[derive(Deserialize)]
+struct ClaimParams {
+    #[serde(deserialize_with = "deserialize_bigint")]
+    invite: u64,
+}
+
+/// An invite to pass on to whoever should sit in `seat`. The game page takes
+/// it as `/game/<id>/?invite=<invite>`.
+#[derive(Serialize)]
+struct InviteView {
+    seat: usize,
+    #[serde(serialize_with = "serialize_bigint")]
+    invite: u64,
+}
+impl InviteView {
+    /// The invites for every seat that hasn't been claimed yet.
+    fn all(game: &GameState) -> Vec<Self> {
+        game.players
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| !p.claimed)
+            .map(|(seat, p)| Self {
+                seat,
+                invite: p.invite,
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct TileView {
+    row: usize,
+    col: usize,
+    name: Option<String>,
+}
+impl TileView {
+    fn new(game: &GameState, char_idx: usize) -> Self {
+        Self {
+            row: char_idx / game.cols,
+            col: char_idx % game.cols,
+            name: game.characters.0[char_idx].name.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CharacterView {
+    row: usize,
+    col: usize,
+    name: Option<String>,
+    attributes: BTreeMap<String, String>,
+    image: String,
+    thumbnail: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PlayerView {
+    seat: usize,
+    claimed: bool,
+    connected: bool,
+    wrong_guesses: usize,
+    correct: bool,
+}
+impl PlayerView {
+    pub(crate) fn new(seat: usize, player: &PlayerState) -> Self {
+        Self {
+            seat,
+            claimed: player.claimed,
+            connected: player.connected,
+            wrong_guesses: player.incorrect_count,
+            correct: player.correct,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct YouView {
+    seat: usize,
+    /// the seat of the player whose character you are guessing
+    target: usize,
+    character: TileView,
+    /// the tiles you have ruled out
+    eliminated: Vec<TileView>,
+}
+
+#[derive(Serialize)]
+struct GameView {
+    rows: usize,
+    cols: usize,
+    scoring: Scoring,
+    max_wrong: Option<usize>,
+    characters: Vec<CharacterView>,
+    players: Vec<PlayerView>,
+    /// the seat whose turn it is, if playing turn based
+    turn: Option<usize>,
+    game_over: bool,
+    you: Option<YouView>,
+}
+impl GameView {
+    fn new(game_id: u64, game: &GameState, uid: u64) -> Self {
+        Self {
+            rows: game.rows,
+            cols: game.cols,
+            scoring: game.scoring,
+            max_wrong: game.max_wrong,
+            characters: game
+                .characters
+                .0
+                .iter()
+                .enumerate()
+                .map(|(idx, c)| {
+                    let (row, col) = (idx / game.cols, idx % game.cols);
+                    CharacterView {
+                        row,
+                        col,
+                        name: c.name.clone(),
+                        attributes: c.attributes.clone(),
+                        image: format!("/game/{game_id}/img-{row}_{col}"),
+                        thumbnail: format!("/game/{game_id}/img-{row}_{col}?size=thumbnail"),
+                    }
+                })
+                .collect(),
+            players: game
+                .players
+                .iter()
+                .enumerate()
+                .map(|(seat, p)| PlayerView::new(seat, p))
+                .collect(),
+            turn: game.turn.and_then(|turn| game.seat(turn)),
+            game_over: game.outcome.is_some(),
+            you: game.player(uid).and_then(|p| {
+                Some(YouView {
+                    seat: game.seat(uid)?,
+                    target: game.seat(game.opponent(uid)?.id)?,
+                    character: TileView::new(game, p.character),
+                    eliminated: p
+                        .eliminated
+                        .iter()
+                        .map(|&idx| TileView::new(game, idx))
+                        .collect(),
+                })
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ResultsPlayerView {
+    #[serde(flatten)]
+    player: PlayerView,
+    character: TileView,
+}
+
+#[derive(Serialize)]
+struct ResultsView {
+    /// the seat of the winner, or `None` if the game was a draw
+    winner: Option<usize>,
+    players: Vec<ResultsPlayerView>,
+}
+impl ResultsView {
+    fn new(game: &GameState) -> Option<Self> {
+        let outcome = game.outcome?;
+        Some(Self {
+            winner: outcome.winner.and_then(|winner| game.seat(winner)),
+            players: game
+                .players
+                .iter()
+                .enumerate()
+                .map(|(seat, p)| ResultsPlayerView {
+                    player: PlayerView::new(seat, p),
+                    character: TileView::new(game, p.character),
+                })
+                .collect(),
+        })
+    }
+}
+
+struct ApiError {
+    status: StatusCode,
+    message: String,
+    /// what was wrong with an uploaded pack
+    report: Option<PackReport>,
+}
+impl ApiError {
+    fn new(status: StatusCode, message: impl fmt::Display) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            report: None,
+        }
+    }
+
+    fn with_report(mut self, report: Option<PackReport>) -> Self {
+        self.report = report;
+        self
+    }
+}
+impl From<SessionRejection> for ApiError {
+    fn from(e: SessionRejection) -> Self {
+        Self::new(e.status(), e.message())
+    }
+}
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = match self.report {
+            Some(report) => json!({ "error": self.message, "report": report }),
+            None => json!({ "error": self.message }),
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use crate::utils::SyncMutex;
+
+/// Content addressed store of image data on disk, keyed by SHA-256.
+///
+/// Blobs are reference counted by the [`Blob`] handles given out for them.
+/// Blobs with no handles stay on disk so identical uploads can reuse them, and
+/// are evicted least recently used first once the store exceeds its size limit.
+#[derive(Clone)]
+pub struct BlobStore(Arc<SyncMutex<BlobStoreInner>>);
+
+struct BlobStoreInner {
+    dir: PathBuf,
+    max_size: usize,
+    size: usize,
+    blobs: HashMap<BlobHash, BlobEntry>,
+}
+
+struct BlobEntry {
+    size: usize,
+    refs: usize,
+    last_used: SystemTime,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobHash([u8; 32]);
+impl fmt::Display for BlobHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Debug for BlobHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+impl std::str::FromStr for BlobHash {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(anyhow!("invalid blob hash {s:?}"));
+        }
+        let mut res = [0; 32];
+        for (i, b) in res.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(Self(res))
+    }
+}
+
+impl BlobStore {
+    /// Opens the store in `dir`, indexing any blobs left over from a previous run.
+    pub fn open(dir: impl AsRef<Path>, max_size: usize) -> Result<Self, anyhow::Error> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+        let mut blobs = HashMap::new();
+        let mut size = 0;
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().ends_with(".tmp") {
+                // left behind by a write that was interrupted
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            let Some(hash) = entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<BlobHash>().ok())
+            else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            size += metadata.len() as usize;
+            blobs.insert(
+                hash,
+                BlobEntry {
+                    size: metadata.len() as usize,
+                    refs: 0,
+                    last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                },
+            );
+        }
+        eprintln!("blob store has {} items", blobs.len());
+        Ok(Self(Arc::new(SyncMutex::new(BlobStoreInner {
+            dir,
+            max_size,
+            size,
+            blobs,
+        }))))
+    }
+
+    /// Total size of the blobs that are currently in use.
+    pub fn referenced_size(&self) -> usize {
+        self.0.peek(|s| {
+            s.blobs
+                .values()
+                .filter(|b| b.refs > 0)
+                .map(|b| b.size)
+                .sum()
+        })
+    }
+
+    /// Adds `data` to the store, or takes another reference to it if it is
+    /// already there. The file is written before the store is locked, so
+    /// readers aren't held up waiting for it to reach the disk.
+    pub fn insert(&self, data: &[u8]) -> Result<Blob, anyhow::Error> {
+        let hash = BlobHash(Sha256::digest(data).into());
+        let blob = || Blob {
+            hash,
+            store: self.clone(),
+        };
+        let dir = self.0.mutate(|s| match s.blobs.get_mut(&hash) {
+            Some(entry) => {
+                entry.refs += 1;
+                entry.last_used = SystemTime::now();
+                None
+            }
+            None => Some(s.dir.clone()),
+        });
+        let Some(dir) = dir else {
+            return Ok(blob());
+        };
+        let path = dir.join(hash.to_string());
+        // unique, so concurrent uploads of the same image don't share it
+        let tmp = path.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+        let written = (|| {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+            self.0.mutate(|s| {
+                if let Some(entry) = s.blobs.get_mut(&hash) {
+                    // stored by someone else while we were writing
+                    entry.refs += 1;
+                    entry.last_used = SystemTime::now();
+                    return Ok(false);
+                }
+                s.evict(data.len())?;
+                fs::rename(&tmp, &path)?;
+                s.size += data.len();
+                s.blobs.insert(
+                    hash,
+                    BlobEntry {
+                        size: data.len(),
+                        refs: 1,
+                        last_used: SystemTime::now(),
+                    },
+                );
+                Ok::<_, anyhow::Error>(true)
+            })
+        })();
+        if !matches!(written, Ok(true)) {
+            let _ = fs::remove_file(&tmp);
+        }
+        written.map(|_| blob())
+    }
+
+    /// Gets a handle to a blob that is already in the store.
+    pub fn get(&self, hash: BlobHash) -> Option<Blob> {
+        self.0.mutate(|s| {
+            let entry = s.blobs.get_mut(&hash)?;
+            entry.refs += 1;
+            entry.last_used = SystemTime::now();
+            Some(Blob {
+                hash,
+                store: self.clone(),
+            })
+        })
+    }
+}
+impl BlobStoreInner {
+    /// Removes unreferenced blobs, least recently used first, until there is
+    /// room for `needed` more bytes.
+    fn evict(&mut self, needed: usize) -> Result<(), anyhow::Error> {
+        if self.size + needed <= self.max_size {
+            return Ok(());
+        }
+        let mut unused = self
+            .blobs
+            .iter()
+            .filter(|(_, b)| b.refs == 0)
+            .map(|(hash, b)| (b.last_used, *hash))
+            .collect::<Vec<_>>();
+        unused.sort_by_key(|(last_used, _)| *last_used);
+        for (_, hash) in unused {
+            if self.size + needed <= self.max_size {
+                break;
+            }
+            match fs::remove_file(self.dir.join(hash.to_string())) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            if let Some(entry) = self.blobs.remove(&hash) {
+                self.size -= entry.size;
+            }
+        }
+        if self.size + needed > self.max_size {
+            return Err(anyhow!("character pack storage is full"));
+        }
+        Ok(())
+    }
+}
+
+/// A reference counted handle to a blob in a [`BlobStore`].
+pub struct Blob {
+    hash: BlobHash,
+    store: BlobStore,
+}
+impl Blob {
+    pub fn hash(&self) -> BlobHash {
+        self.hash
+    }
+
+    pub async fn read(&self) -> Result<Bytes, anyhow::Error> {
+        let path = self.store.0.mutate(|s| {
+            if let Some(entry) = s.blobs.get_mut(&self.hash) {
+                entry.last_used = SystemTime::now();
+            }
+            s.dir.join(self.hash.to_string())
+        });
+        Ok(tokio::fs::read(path).await?.into())
+    }
+}
+impl Clone for Blob {
+    fn clone(&self) -> Self {
+        // this handle keeps the blob from being evicted, so it's still there
+        self.store.0.mutate(|s| {
+            if let Some(entry) = s.blobs.get_mut(&self.hash) {
+                entry.refs += 1;
+            }
+        });
+        Self {
+            hash: self.hash,
+            store: self.store.clone(),
+        }
+    }
+}
+impl Drop for Blob {
+    fn drop(&mut self) {
+        self.store.0.mutate(|s| {
+            if let Some(entry) = s.blobs.get_mut(&self.hash) {
+                entry.refs -= 1;
+            }
+        })
+    }
+}
+impl fmt::Debug for Blob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Blob").field(&self.hash).finish()
+    }
+}
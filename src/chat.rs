@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+/// Longest chat message accepted, in characters of markdown.
+const MAX_MESSAGE_LEN: usize = 500;
+
+/// The formatting markdown can produce that is safe to show other players.
+/// Anything else, including every attribute but a link's `href`, is removed.
+static SANITIZER: LazyLock<ammonia::Builder<'static>> = LazyLock::new(|| {
+    let mut builder = ammonia::Builder::empty();
+    builder
+        .tags(HashSet::from([
+            "a",
+            "b",
+            "blockquote",
+            "br",
+            "code",
+            "del",
+            "em",
+            "i",
+            "li",
+            "ol",
+            "p",
+            "pre",
+            "s",
+            "strong",
+            "ul",
+        ]))
+        .tag_attributes([("a", HashSet::from(["href"]))].into())
+        .url_schemes(HashSet::from(["http", "https"]))
+        .link_rel(Some("noopener noreferrer nofollow"));
+    builder
+});
+
+/// Renders a chat message written in markdown to html that is safe to insert
+/// into another player's page.
+pub fn render_message(content: &str) -> Result<String, anyhow::Error> {
+    let content = content.trim();
+    if content.is_empty() {
+        return Err(anyhow!("message is empty"));
+    }
+    if content.chars().count() > MAX_MESSAGE_LEN {
+        return Err(anyhow!(
+            "message is longer than {MAX_MESSAGE_LEN} characters"
+        ));
+    }
+    let html = markdown::to_html(content);
+    let html = SANITIZER
+        .clean(
+            html.trim_start_matches("<p>")
+                .trim_end()
+                .trim_end_matches("</p>"),
+        )
+        .to_string();
+    // e.g. a message that was only an image
+    if html.trim().is_empty() {
+        return Err(anyhow!("message is empty"));
+    }
+    Ok(html)
+}
+
+/// The emoji players can react to chat messages with.
+pub const REACTIONS: [&str; 6] = ["👍", "👎", "😂", "😮", "🤔", "❤️"];
+
+/// A canned answer to a question asked in chat.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuickReply {
+    Yes,
+    No,
+    Sometimes,
+    CantTell,
+}
+impl QuickReply {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Yes => "Yes",
+            Self::No => "No",
+            Self::Sometimes => "Sometimes",
+            Self::CantTell => "Can't tell",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(content: &str) -> String {
+        render_message(content).unwrap()
+    }
+
+    fn rejected(content: &str) -> String {
+        render_message(content).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(
+            rendered("**bold** [link](https://example.com)"),
+            "<strong>bold</strong> <a href=\"https://example.com\" \
+             rel=\"noopener noreferrer nofollow\">link</a>"
+        );
+    }
+
+    #[test]
+    fn script() {
+        assert_eq!(
+            rendered("<script>alert(1)</script>"),
+            "&lt;script&gt;alert(1)&lt;/script&gt;"
+        );
+    }
+
+    #[test]
+    fn img_onerror() {
+        assert_eq!(
+            rendered("<img src=x onerror=alert(1)>"),
+            "&lt;img src=x onerror=alert(1)&gt;"
+        );
+    }
+
+    #[test]
+    fn javascript_link() {
+        assert_eq!(
+            rendered("[x](javascript:alert(1))"),
+            "<a href=\"\" rel=\"noopener noreferrer nofollow\">x</a>"
+        );
+    }
+
+    #[test]
+    fn link_onclick() {
+        assert_eq!(
+            rendered("<a href=\"https://x\" onclick=\"alert(1)\">x</a>"),
+            "&lt;a href=\"https://x\" onclick=\"alert(1)\"&gt;x&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn svg_onload() {
+        assert_eq!(
+            rendered("<svg onload=alert(1)>"),
+            "&lt;svg onload=alert(1)&gt;"
+        );
+    }
+
+    #[test]
+    fn too_long() {
+        assert_eq!(
+            rejected(&"a".repeat(MAX_MESSAGE_LEN + 1)),
+            "message is longer than 500 characters"
+        );
+        assert_eq!(
+            rendered(&"é".repeat(MAX_MESSAGE_LEN)),
+            "é".repeat(MAX_MESSAGE_LEN)
+        );
+    }
+
+    #[test]
+    fn whitespace_only() {
+        assert_eq!(rejected(" \n\t "), "message is empty");
+        assert_eq!(
+            rejected("![](https://example.com/a.png)"),
+            "message is empty"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+// shared by the player and spectator pages, each of which defines `sync` to
+// catch up with a snapshot of the game
+
+/**
+ * @type {HTMLElement}
+ */
+let eventLog
+
+function escape_html(s) {
+  const el = document.createElement('span')
+  el.textContent = s
+  return el.innerHTML
+}
+
+/**
+ * Counts a reaction on the chat message logged as event `message`.
+ */
+function add_reaction(message, emoji) {
+  const reactions = document.querySelector(`#msg-${message} .reactions`)
+  if (!reactions) return
+  const existing = [...reactions.children].find(
+    (r) => r.dataset.emoji === emoji,
+  )
+  if (existing) {
+    const count = existing.querySelector('.count')
+    count.innerHTML = Number(count.innerHTML) + 1
+  } else {
+    reactions.innerHTML += `<span class="reaction" data-emoji="${escape_html(emoji)}">${escape_html(emoji)} <span class="count">1</span></span>`
+  }
+}
+
+let reconnectDelay = 1000
+
+/**
+ * Opens the game's websocket at `url`, calling `reconnect` after a growing
+ * delay if it drops. Events we have already seen are skipped, and logged ones
+ * are added to the sidebar before being passed to `on_event`.
+ *
+ * @returns {WebSocket}
+ */
+function open_events(url, reconnect, on_event) {
+  const ws = new WebSocket(url)
+  ws.addEventListener('open', () => {
+    reconnectDelay = 1000
+  })
+  ws.addEventListener('close', (ev) => {
+    // the server only closes cleanly once the game is gone
+    if (ev.code === 1000) return
+    setTimeout(reconnect, reconnectDelay)
+    reconnectDelay = Math.min(reconnectDelay * 2, 30000)
+  })
+  ws.addEventListener('message', (ev) => {
+    const event = JSON.parse(ev.data)
+    if (event.type === 'sync') {
+      sync(event)
+      return
+    }
+    if (event.seq != null) {
+      // already seen before we reconnected
+      if (event.seq <= seq) return
+      seq = event.seq
+    }
+    // logged events come with their line for the sidebar, rendered by the
+    // server so it reads the same as the log does after a reload
+    if (event.log) eventLog.innerHTML += event.log
+    on_event(event)
+  })
+  return ws
+}
@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{QuickReply, REACTIONS};
+use crate::utils::{escape_html, html_to_text};
+
+/// How many entries each game's log keeps before dropping the oldest.
+const MAX_LOG_LEN: usize = 500;
+
+/// Something worth remembering that happened in a game. Players are referred
+/// to by seat, so the log can be handed out without giving away anyone's id.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum LogEvent {
+    Message {
+        seat: usize,
+        /// html, as it was sent to the other players
+        content: String,
+    },
+    QuickReply {
+        seat: usize,
+        reply: QuickReply,
+    },
+    /// an emoji reaction to the chat message logged as event `message`
+    React {
+        seat: usize,
+        message: u64,
+        emoji: String,
+    },
+    Answer {
+        seat: usize,
+        attribute: String,
+        value: String,
+        answer: bool,
+    },
+    Correct {
+        seat: usize,
+        tries: usize,
+    },
+    Incorrect {
+        seat: usize,
+    },
+    Rematch {
+        seat: usize,
+    },
+    Restart,
+    GameOver {
+        /// `None` if the game was a draw
+        winner: Option<usize>,
+    },
+}
+impl LogEvent {
+    fn seat(&self) -> Option<usize> {
+        match self {
+            Self::Message { seat, .. }
+            | Self::QuickReply { seat, .. }
+            | Self::React { seat, .. }
+            | Self::Answer { seat, .. }
+            | Self::Correct { seat, .. }
+            | Self::Incorrect { seat }
+            | Self::Rematch { seat } => Some(*seat),
+            Self::Restart | Self::GameOver { .. } => None,
+        }
+    }
+
+    /// Whether players can react to the event.
+    fn is_chat(&self) -> bool {
+        matches!(self, Self::Message { .. } | Self::QuickReply { .. })
+    }
+
+    /// The line clients add to the sidebar when event `seq` happens, which
+    /// is the same as the one [`EventLog::html`] shows once the page is
+    /// reloaded. `None` for reactions, which are shown on the message they
+    /// are for instead.
+    pub fn html(&self, seq: u64, viewer: Option<usize>, num_players: usize) -> Option<String> {
+        self.line(seq, viewer, num_players, &[])
+    }
+
+    /// Renders the event as a line of the sidebar, along with the `reactions`
+    /// it has had if it is a chat message.
+    fn line(
+        &self,
+        seq: u64,
+        viewer: Option<usize>,
+        num_players: usize,
+        reactions: &[(&str, usize)],
+    ) -> Option<String> {
+        if let Self::React { .. } = self {
+            return None;
+        }
+        let seat = self.seat();
+        let mine = match viewer {
+            Some(viewer) => seat.is_none_or(|seat| seat == viewer),
+            // spectators see the seats alternate colours
+            None => seat.is_none_or(|seat| seat % 2 == 0),
+        };
+        let class = if mine { "mine" } else { "theirs" };
+        let line = self.describe(viewer, num_players);
+        if !self.is_chat() {
+            return Some(format!("<p class=\"{class}\">{line}</p>"));
+        }
+        Some(format!(
+            include_str!("./chat-line.html.template"),
+            seq = seq,
+            class = class,
+            line = line,
+            reactions = reactions
+                .iter()
+                .map(|(emoji, count)| reaction_html(emoji, *count))
+                .collect::<String>(),
+            buttons = if viewer.is_some() {
+                REACTIONS
+                    .iter()
+                    .map(|emoji| {
+                        format!("<button onclick=\"react({seq}, '{emoji}')\">{emoji}</button>")
+                    })
+                    .collect()
+            } else {
+                String::new()
+            },
+        ))
+    }
+
+    /// Describes the event as html, from the point of view of the player in
+    /// seat `viewer`, or of a spectator if `None`.
+    fn describe(&self, viewer: Option<usize>, num_players: usize) -> String {
+        // how to refer to a player, using `other` for the only other player
+        // in a two player game
+        let who = |seat: usize, other: &str| {
+            if viewer == Some(seat) {
+                "You".to_owned()
+            } else if viewer.is_some() && num_players == 2 {
+                other.to_owned()
+            } else {
+                format!("Player {}", seat + 1)
+            }
+        };
+        let whose = |seat: usize| {
+            let target = (seat + 1) % num_players;
+            if viewer == Some(target) {
+                "your".to_owned()
+            } else {
+                format!("Player {}'s", target + 1)
+            }
+        };
+        let tries = |tries: usize| format!("{tries} {}", if tries == 1 { "try" } else { "tries" });
+        match self {
+            Self::Message { seat, content } => {
+                format!("<b class=\"title\">{}: </b>{content}", who(*seat, "Them"))
+            }
+            Self::QuickReply { seat, reply } => format!(
+                "<b class=\"title\">{}: </b><i class=\"quick-reply\">{}</i>",
+                who(*seat, "Them"),
+                escape_html(reply.label()),
+            ),
+            Self::React { seat, emoji, .. } => format!(
+                "<b class=\"title\">{} reacted with {}.</b>",
+                who(*seat, "They"),
+                escape_html(emoji),
+            ),
+            Self::Answer {
+                seat,
+                attribute,
+                value,
+                answer,
+            } => format!(
+                "<b class=\"title\">{} asked: </b>{}: {}? <b>{}</b>",
+                who(*seat, "They"),
+                escape_html(attribute),
+                escape_html(value),
+                if *answer {
+                    "<span style=\"color: green\">Yes</span>"
+                } else {
+                    "<span style=\"color: red\">No</span>"
+                },
+            ),
+            Self::Correct { seat, .. } if viewer == Some(*seat) => {
+                "<b class=\"title\">You guessed <span style=\"color: green\">correctly</span>!</b>"
+                    .to_owned()
+            }
+            Self::Correct { seat, tries: n } => format!(
+                "<b class=\"title\">{} <span style=\"color: green\">correctly</span> guessed {} character in {}!</b>",
+                who(*seat, "The other player"),
+                whose(*seat),
+                tries(*n),
+            ),
+            Self::Incorrect { seat } if viewer == Some(*seat) => {
+                "<b class=\"title\">You guessed <span style=\"color: red\">incorrectly</span>.</b>"
+                    .to_owned()
+            }
+            Self::Incorrect { seat } => format!(
+                "<b class=\"title\">{} <span style=\"color: red\">incorrectly</span> guessed {} character.</b>",
+                who(*seat, "The other player"),
+                whose(*seat),
+            ),
+            Self::Rematch { seat } => format!(
+                "<b class=\"title\">{} {} a rematch!</b>",
+                who(*seat, "The other player"),
+                if viewer == Some(*seat) { "want" } else { "wants" },
+            ),
+            Self::Restart => "<b class=\"title\">A new round has started.</b>".to_owned(),
+            Self::GameOver { winner } => format!(
+                "<b class=\"title\">Game over! {}</b>",
+                match (winner, viewer) {
+                    (None, _) => "The game is a draw.".to_owned(),
+                    (Some(winner), Some(viewer)) if *winner == viewer => "You won!".to_owned(),
+                    (Some(_), Some(_)) => "You lost.".to_owned(),
+                    (Some(winner), None) => format!("Player {} won!", winner + 1),
+                }
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// the sequence number of the event that was logged
+    pub seq: u64,
+    /// seconds since the unix epoch
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: LogEvent,
+}
+
+/// The chat and game events of a game, oldest first.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EventLog(VecDeque<LogEntry>);
+impl EventLog {
+    pub fn push(&mut self, seq: u64, event: LogEvent) {
+        if self.0.len() == MAX_LOG_LEN {
+            self.0.pop_front();
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.0.push_back(LogEntry { seq, time, event });
+    }
+
+    pub fn entries(&self) -> &VecDeque<LogEntry> {
+        &self.0
+    }
+
+    /// Whether event `seq` is a chat message that is still in the log.
+    pub fn is_chat(&self, seq: u64) -> bool {
+        self.0.iter().any(|e| e.seq == seq && e.event.is_chat())
+    }
+
+    /// Whether the player in `seat` has already reacted to `message` with
+    /// `emoji`.
+    pub fn has_reacted(&self, seat: usize, message: u64, emoji: &str) -> bool {
+        self.0.iter().any(|e| {
+            matches!(
+                &e.event,
+                LogEvent::React { seat: s, message: m, emoji: e }
+                    if *s == seat && *m == message && e == emoji
+            )
+        })
+    }
+
+    /// Renders the log for the sidebar, from the point of view of the player
+    /// in seat `viewer`, or of a spectator if `None`.
+    pub fn html(&self, viewer: Option<usize>, num_players: usize) -> String {
+        // reactions are shown on the message they are for, rather than on a
+        // line of their own
+        let mut reactions = BTreeMap::<u64, Vec<(&str, usize)>>::new();
+        for entry in &self.0 {
+            if let LogEvent::React { message, emoji, .. } = &entry.event {
+                let counts = reactions.entry(*message).or_default();
+                match counts.iter_mut().find(|(e, _)| e == emoji) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((emoji, 1)),
+                }
+            }
+        }
+        self.0
+            .iter()
+            .filter_map(|entry| {
+                entry.event.line(
+                    entry.seq,
+                    viewer,
+                    num_players,
+                    reactions.get(&entry.seq).map_or(&[], Vec::as_slice),
+                )
+            })
+            .collect()
+    }
+
+    /// Renders the log as plain text, one line per entry, with UTC times.
+    pub fn text(&self, num_players: usize) -> String {
+        self.0
+            .iter()
+            .map(|entry| {
+                let secs = entry.time % (60 * 60 * 24);
+                format!(
+                    "[{:02}:{:02}:{:02}] {}\n",
+                    secs / 3600,
+                    secs / 60 % 60,
+                    secs % 60,
+                    html_to_text(&entry.event.describe(None, num_players))
+                )
+            })
+            .collect()
+    }
+}
+
+fn reaction_html(emoji: &str, count: usize) -> String {
+    let emoji = escape_html(emoji);
+    format!(
+        "<span class=\"reaction\" data-emoji=\"{emoji}\">{emoji} <span class=\"count\">{count}</span></span>"
+    )
+}
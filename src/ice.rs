@@ -0,0 +1,164 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha1::Sha1;
+use tokio::net::UdpSocket;
+
+use crate::utils::env_var;
+
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// The STUN and TURN servers browsers should use to set up voice calls.
+///
+/// Configured with:
+/// - `IMPOSTER_ROSTER_STUN_URLS`: comma separated `stun:` urls. There are
+///   none by default, so players' addresses are only ever sent to a third
+///   party's STUN server if one is configured here
+/// - `IMPOSTER_ROSTER_STUN_PORT`: a udp port to answer STUN requests on
+///   ourselves
+/// - `IMPOSTER_ROSTER_TURN_URLS`: comma separated `turn:` or `turns:` urls
+/// - `IMPOSTER_ROSTER_TURN_SECRET`: the secret shared with the TURN server,
+///   as in coturn's `static-auth-secret`
+#[derive(Clone, Debug, Default)]
+pub struct IceConfig {
+    stun_urls: Vec<String>,
+    pub stun_port: Option<u16>,
+    turn_urls: Vec<String>,
+    turn_secret: Option<Vec<u8>>,
+}
+impl IceConfig {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let urls = |s: String| {
+            s.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+        Ok(Self {
+            stun_urls: env_var("IMPOSTER_ROSTER_STUN_URLS")?
+                .map(urls)
+                .unwrap_or_default(),
+            stun_port: env_var("IMPOSTER_ROSTER_STUN_PORT")?,
+            turn_urls: env_var("IMPOSTER_ROSTER_TURN_URLS")?
+                .map(urls)
+                .unwrap_or_default(),
+            turn_secret: env_var("IMPOSTER_ROSTER_TURN_SECRET")?.map(String::into_bytes),
+        })
+    }
+
+    /// The `iceServers` of an `RTCConfiguration` for user `uid`, reached at
+    /// `host`. TURN credentials stop working after `ttl`.
+    pub fn servers(&self, host: &str, uid: u64, ttl: Duration) -> Vec<IceServer> {
+        let mut stun_urls = self.stun_urls.clone();
+        if let Some(port) = self.stun_port {
+            // drop the port the page was served on, if any
+            let host = if host.starts_with('[') {
+                host.split_inclusive(']').next()
+            } else {
+                host.split(':').next()
+            }
+            .unwrap_or(host);
+            stun_urls.insert(0, format!("stun:{host}:{port}"));
+        }
+        let mut servers = Vec::new();
+        if !stun_urls.is_empty() {
+            servers.push(IceServer {
+                urls: stun_urls,
+                username: None,
+                credential: None,
+            });
+        }
+        if let (false, Some(secret)) = (self.turn_urls.is_empty(), &self.turn_secret) {
+            // the TURN REST api: the username says when the credential
+            // expires, and the credential proves we issued it
+            let expires = (SystemTime::now() + ttl)
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let username = format!("{expires}:{uid}");
+            let mut mac =
+                Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts keys of any length");
+            mac.update(username.as_bytes());
+            servers.push(IceServer {
+                urls: self.turn_urls.clone(),
+                username: Some(username),
+                credential: Some(
+                    base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes()),
+                ),
+            });
+        }
+        servers
+    }
+}
+
+/// One entry of `RTCConfiguration.iceServers`.
+#[derive(Serialize)]
+pub struct IceServer {
+    urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credential: Option<String>,
+}
+
+/// Answers STUN binding requests on `port`, telling browsers the address
+/// their requests came from. This is all a browser needs from a STUN server
+/// to find its public address.
+pub async fn serve_stun(port: u16) -> Result<(), anyhow::Error> {
+    let socket = match UdpSocket::bind(("::", port)).await {
+        Ok(socket) => socket,
+        // no ipv6 on this host
+        Err(_) => UdpSocket::bind(("0.0.0.0", port)).await?,
+    };
+    let mut buf = [0; 1500];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Some(res) = binding_response(&buf[..len], from) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&res, from).await {
+            eprintln!("stun: {e}");
+        }
+    }
+}
+
+fn binding_response(req: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+    if req.len() < 20
+        || u16::from_be_bytes([req[0], req[1]]) != STUN_BINDING_REQUEST
+        || u32::from_be_bytes([req[4], req[5], req[6], req[7]]) != STUN_MAGIC_COOKIE
+    {
+        return None;
+    }
+    let transaction_id = &req[8..20];
+    let cookie = STUN_MAGIC_COOKIE.to_be_bytes();
+    let port = from.port() ^ (STUN_MAGIC_COOKIE >> 16) as u16;
+    let mut attr = vec![0];
+    match from.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            attr.push(0x01);
+            attr.extend(port.to_be_bytes());
+            attr.extend(ip.octets().iter().zip(cookie).map(|(a, b)| a ^ b));
+        }
+        IpAddr::V6(ip) => {
+            attr.push(0x02);
+            attr.extend(port.to_be_bytes());
+            let key = cookie.iter().chain(transaction_id);
+            attr.extend(ip.octets().iter().zip(key).map(|(a, b)| a ^ b));
+        }
+    }
+    let mut res = Vec::with_capacity(24 + attr.len());
+    res.extend(STUN_BINDING_RESPONSE.to_be_bytes());
+    res.extend((4 + attr.len() as u16).to_be_bytes());
+    res.extend(cookie);
+    res.extend(transaction_id);
+    res.extend(STUN_XOR_MAPPED_ADDRESS.to_be_bytes());
+    res.extend((attr.len() as u16).to_be_bytes());
+    res.extend(attr);
+    Some(res)
+}
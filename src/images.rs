@@ -0,0 +1,338 @@
+use std::fmt;
+use std::io::Cursor;
+
+use anyhow::anyhow;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Rgb, RgbImage};
+
+use crate::utils::env_var;
+
+/// The formats packs can contain, as told by their content rather than by
+/// their file names.
+const ALLOWED_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Bmp,
+];
+
+/// How uploaded character images are reshaped before they're stored.
+///
+/// Configured with:
+/// - `IMPOSTER_ROSTER_IMAGE_ASPECT`: the `width:height` images are cropped
+///   to, around their centre
+/// - `IMPOSTER_ROSTER_IMAGE_SIZE`: the longest side of a full size image, in
+///   pixels
+/// - `IMPOSTER_ROSTER_THUMBNAIL_SIZE`: the longest side of a thumbnail
+/// - `IMPOSTER_ROSTER_IMAGE_QUALITY`: the jpeg quality, from 1 to 100
+#[derive(Clone, Debug)]
+pub struct ImageConfig {
+    aspect: (u32, u32),
+    size: u32,
+    thumbnail_size: u32,
+    quality: u8,
+}
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            aspect: (1, 1),
+            size: 800,
+            thumbnail_size: 256,
+            quality: 85,
+        }
+    }
+}
+impl ImageConfig {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let mut config = Self::default();
+        if let Some(aspect) = env_var::<String>("IMPOSTER_ROSTER_IMAGE_ASPECT")? {
+            let (w, h) = aspect
+                .split_once(':')
+                .ok_or_else(|| anyhow!("image aspect must look like 3:4, not {aspect:?}"))?;
+            config.aspect = (w.trim().parse()?, h.trim().parse()?);
+            if config.aspect.0 == 0 || config.aspect.1 == 0 {
+                return Err(anyhow!("image aspect can't be zero"));
+            }
+        }
+        if let Some(size) = env_var("IMPOSTER_ROSTER_IMAGE_SIZE")? {
+            config.size = size;
+        }
+        if let Some(size) = env_var("IMPOSTER_ROSTER_THUMBNAIL_SIZE")? {
+            config.thumbnail_size = size;
+        }
+        if let Some(quality) = env_var("IMPOSTER_ROSTER_IMAGE_QUALITY")? {
+            config.quality = quality;
+            if !(1..=100).contains(&config.quality) {
+                return Err(anyhow!("image quality must be between 1 and 100"));
+            }
+        }
+        if config.size == 0 || config.thumbnail_size == 0 {
+            return Err(anyhow!("image sizes can't be zero"));
+        }
+        Ok(config)
+    }
+
+    /// Decodes an uploaded image in the format [`sniff`] found, turns it the
+    /// right way up, crops it to the configured aspect and shrinks it,
+    /// returning the full size jpeg and its thumbnail. Animated images keep
+    /// only their first frame.
+    pub fn normalize(&self, data: &[u8], format: ImageFormat) -> Result<NormalizedImage, Rejected> {
+        let decode = || {
+            let mut decoder = ImageReader::with_format(Cursor::new(data), format).into_decoder()?;
+            let orientation = decoder.orientation()?;
+            Ok::<_, image::ImageError>((DynamicImage::from_decoder(decoder)?, orientation))
+        };
+        let (mut img, orientation) = decode().map_err(|e| match e {
+            image::ImageError::Limits(e) => Rejected::TooLarge(e.to_string()),
+            e => Rejected::Corrupt(format!("couldn't decode image: {e}")),
+        })?;
+        img.apply_orientation(orientation);
+
+        let (w, h) = (img.width() as u64, img.height() as u64);
+        let (aw, ah) = (self.aspect.0 as u64, self.aspect.1 as u64);
+        let (cw, ch) = if w * ah > h * aw {
+            (h * aw / ah, h)
+        } else {
+            (w, w * ah / aw)
+        };
+        if cw == 0 || ch == 0 {
+            return Err(Rejected::Corrupt("image is too small".to_owned()));
+        }
+        let img = img.crop_imm(
+            ((w - cw) / 2) as u32,
+            ((h - ch) / 2) as u32,
+            cw as u32,
+            ch as u32,
+        );
+
+        let image = self.encode(&shrink(&img, self.size))?;
+        let thumbnail = self.encode(&shrink(&img, self.thumbnail_size))?;
+        Ok(NormalizedImage { image, thumbnail })
+    }
+
+    fn encode(&self, img: &DynamicImage) -> Result<Vec<u8>, Rejected> {
+        let mut buf = Vec::new();
+        flatten(img)
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buf, self.quality))
+            .map_err(|e| Rejected::Corrupt(format!("couldn't encode image: {e}")))?;
+        Ok(buf)
+    }
+}
+
+/// Why an uploaded file couldn't be used as an image.
+#[derive(Debug)]
+pub enum Rejected {
+    NotImage(String),
+    /// an image, but in a format we don't accept
+    Unsupported(String),
+    TooLarge(String),
+    Corrupt(String),
+}
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotImage(reason)
+            | Self::Unsupported(reason)
+            | Self::TooLarge(reason)
+            | Self::Corrupt(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// Works out what format an uploaded file is in from its first few bytes,
+/// failing if it isn't one we accept.
+pub fn sniff(data: &[u8]) -> Result<ImageFormat, Rejected> {
+    if let Ok(format) = image::guess_format(data) {
+        if !ALLOWED_FORMATS.contains(&format) {
+            return Err(Rejected::Unsupported(format!(
+                "{format:?} images aren't supported"
+            )));
+        }
+        return Ok(format);
+    }
+    if data.starts_with(b"PK\x03\x04")
+        || data.starts_with(b"\x1f\x8b")
+        || data.get(257..262) == Some(b"ustar")
+    {
+        return Err(Rejected::NotImage(
+            "archives inside a pack aren't unpacked".to_owned(),
+        ));
+    }
+    // svgs can run scripts, so they're turned away along with any other
+    // markup, whatever they are named
+    let text = data
+        .strip_prefix(b"\xef\xbb\xbf")
+        .unwrap_or(data)
+        .trim_ascii_start();
+    if text.starts_with(b"<") {
+        let head = String::from_utf8_lossy(&text[..text.len().min(1024)]).to_lowercase();
+        if head.contains("<svg") {
+            return Err(Rejected::Unsupported(
+                "svg images aren't allowed".to_owned(),
+            ));
+        }
+        return Err(Rejected::NotImage(
+            "this is html or xml, not an image".to_owned(),
+        ));
+    }
+    Err(Rejected::NotImage(
+        "not a png, jpeg, gif, webp or bmp image".to_owned(),
+    ))
+}
+
+pub struct NormalizedImage {
+    pub image: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+}
+
+/// Scales `img` down so its longest side is at most `size`. Small images are
+/// left as they are, rather than blown up.
+fn shrink(img: &DynamicImage, size: u32) -> DynamicImage {
+    if img.width() <= size && img.height() <= size {
+        img.clone()
+    } else {
+        img.resize(size, size, FilterType::CatmullRom)
+    }
+}
+
+/// Jpegs can't be transparent, so transparent pixels are drawn over white.
+fn flatten(img: &DynamicImage) -> RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let over_white = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        Rgb([over_white(r), over_white(g), over_white(b)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn config(aspect: (u32, u32)) -> ImageConfig {
+        ImageConfig {
+            aspect,
+            ..ImageConfig::default()
+        }
+    }
+
+    /// A png whose left half is `left` and right half is `right`.
+    fn png(width: u32, height: u32, left: Rgba<u8>, right: Rgba<u8>) -> Vec<u8> {
+        let half = |x| if x < width / 2 { left } else { right };
+        let img = RgbaImage::from_fn(width, height, |x, _| half(x));
+        let mut buf = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    fn decode(jpeg: &[u8]) -> DynamicImage {
+        image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg).unwrap()
+    }
+
+    /// Whether two colours are the same, give or take jpeg's losses.
+    fn close(a: Rgba<u8>, b: [u8; 3]) -> bool {
+        a.0.iter().zip(b).all(|(a, b)| a.abs_diff(b) < 16)
+    }
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    #[test]
+    fn sniff_by_content() {
+        // real images are recognised whatever they're called
+        assert_eq!(sniff(&png(4, 4, RED, BLUE)).unwrap(), ImageFormat::Png);
+
+        let html = b"<!doctype html><html><body>hi</body></html>";
+        assert!(matches!(sniff(html), Err(Rejected::NotImage(_))));
+
+        let svg = b"\xef\xbb\xbf  <?xml version=\"1.0\"?>\n\
+            <svg xmlns=\"http://www.w3.org/2000/svg\"><script>alert(1)</script></svg>";
+        assert!(matches!(sniff(svg), Err(Rejected::Unsupported(_))));
+
+        let tiff = b"II\x2a\0\x08\0\0\0\0\0\0\0";
+        assert!(matches!(sniff(tiff), Err(Rejected::Unsupported(_))));
+
+        let zip = b"PK\x03\x04\0\0\0\0";
+        assert!(matches!(sniff(zip), Err(Rejected::NotImage(_))));
+    }
+
+    #[test]
+    fn crop_to_aspect() {
+        let data = png(300, 100, RED, BLUE);
+        let out = config((1, 1)).normalize(&data, ImageFormat::Png).unwrap();
+        let img = decode(&out.image);
+        assert_eq!(img.dimensions(), (100, 100));
+        // the crop is around the centre, so it takes in both halves
+        assert!(close(img.get_pixel(10, 50), [255, 0, 0]));
+        assert!(close(img.get_pixel(90, 50), [0, 0, 255]));
+
+        let out = config((3, 4)).normalize(&data, ImageFormat::Png).unwrap();
+        assert_eq!(decode(&out.image).dimensions(), (75, 100));
+        let out = config((4, 1)).normalize(&data, ImageFormat::Png).unwrap();
+        assert_eq!(decode(&out.image).dimensions(), (300, 75));
+    }
+
+    #[test]
+    fn shrink_large_images() {
+        let data = png(2000, 1000, RED, BLUE);
+        let out = config((2, 1)).normalize(&data, ImageFormat::Png).unwrap();
+        assert_eq!(decode(&out.image).dimensions(), (800, 400));
+        assert_eq!(decode(&out.thumbnail).dimensions(), (256, 128));
+    }
+
+    #[test]
+    fn small_images_not_upscaled() {
+        let data = png(64, 48, RED, BLUE);
+        let out = config((4, 3)).normalize(&data, ImageFormat::Png).unwrap();
+        assert_eq!(decode(&out.image).dimensions(), (64, 48));
+        assert_eq!(decode(&out.thumbnail).dimensions(), (64, 48));
+    }
+
+    #[test]
+    fn flatten_over_white() {
+        let clear = Rgba([0, 0, 0, 0]);
+        let half_red = Rgba([255, 0, 0, 128]);
+        let data = png(100, 100, clear, half_red);
+        let out = config((1, 1)).normalize(&data, ImageFormat::Png).unwrap();
+        let img = decode(&out.image);
+        assert!(close(img.get_pixel(25, 50), [255, 255, 255]));
+        assert!(close(img.get_pixel(75, 50), [255, 127, 127]));
+
+        let flat = flatten(&image::load_from_memory(&data).unwrap());
+        assert_eq!(flat.get_pixel(0, 0).0, [255, 255, 255]);
+        assert_eq!(flat.get_pixel(99, 0).0, [255, 127, 127]);
+    }
+
+    #[test]
+    fn exif_orientation() {
+        let img = image::load_from_memory(&png(200, 100, RED, BLUE)).unwrap();
+        let mut jpeg = Vec::new();
+        img.to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 95))
+            .unwrap();
+        // an exif segment saying the image has to be turned 90° clockwise
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(b"\x01\x12\0\x03\0\0\0\x01\0\x06\0\0");
+        exif.extend_from_slice(&[0; 4]);
+        let mut segment = b"\xff\xe1".to_vec();
+        segment.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(&exif);
+        jpeg.splice(2..2, segment);
+
+        let out = config((1, 2)).normalize(&jpeg, ImageFormat::Jpeg).unwrap();
+        let img = decode(&out.image);
+        assert_eq!(img.dimensions(), (100, 200));
+        // what was on the left is now on top
+        assert!(close(img.get_pixel(50, 20), [255, 0, 0]));
+        assert!(close(img.get_pixel(50, 180), [0, 0, 255]));
+    }
+}
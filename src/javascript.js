@@ -15,10 +15,117 @@ function guess_mode() {
   }
 }
 
+function game_over() {
+  if (guessing) guess_mode()
+  document.getElementById('game-board').classList.add('game-over')
+  document.getElementById('guess-button').setAttribute('disabled', true)
+  fetch('./results').then(async (res) => {
+    if (res.status === 200) {
+      document.getElementById('results').innerHTML = await res.text()
+    } else {
+      console.error(res)
+    }
+  })
+}
+
+/**
+ * How to refer to the player in `seat` at the start of a sentence.
+ */
+function player_label(seat) {
+  return num_players === 2 ? 'The other player' : `Player ${seat + 1}`
+}
+
+/**
+ * Copies the invite link for `seat` so the host can send it to a friend.
+ */
+function copy_invite(seat) {
+  const link = document.getElementById(`invite-link-${seat}`)
+  navigator.clipboard.writeText(link.value).catch(() => {
+    link.select()
+  })
+}
+
+/**
+ * Replaces the invite for `seat`, so the old link can no longer be used.
+ */
+function reissue_invite(seat) {
+  fetch(`./invites/${seat}`, { method: 'POST' }).then(async (res) => {
+    const json = await res.json()
+    if (res.status === 200) {
+      document.getElementById(`invite-link-${seat}`).value = json.link
+      document.getElementById(`invite-qr-${seat}`).src =
+        `./invites/${seat}/qr.svg?t=${Date.now()}`
+    } else {
+      eventLog.innerHTML += `<p class="mine"><b class="title" style="color: red">${escape_html(json.error)}</b></p>`
+    }
+  })
+}
+
+/**
+ * The turn bar's text when it is `seat`'s turn.
+ */
+function turn_status(seat) {
+  return seat === my_seat
+    ? 'Your turn'
+    : num_players === 2
+      ? 'Their turn'
+      : `${player_label(seat)}'s turn`
+}
+
+/**
+ * Catches up with a snapshot of the game, sent when the events we missed are
+ * no longer available.
+ */
+function sync(snapshot) {
+  if (snapshot.round !== round) {
+    // the board has been reshuffled since the page loaded
+    window.location.reload()
+    return
+  }
+  seq = snapshot.seq
+  const status = document.getElementById('turn-status')
+  if (status && snapshot.turn !== null) {
+    status.innerHTML = turn_status(snapshot.turn)
+  }
+  for (const tile of document.querySelectorAll('#game-board td')) {
+    tile.classList.remove('blackout')
+  }
+  show_eliminated(snapshot.eliminated, true)
+  for (const { row, col, correct } of snapshot.guesses) {
+    const tile = document.getElementById(`idx-${row}_${col}`)
+    tile.classList.remove('blackout')
+    tile.classList.add(correct ? 'correct' : 'incorrect')
+  }
+  connected = snapshot.players.some((p) => p.seat !== my_seat && p.connected)
+  if (callState === null) {
+    if (connected) callButton.removeAttribute('disabled')
+    else callButton.setAttribute('disabled', true)
+  }
+  document.getElementById('spectator-count').innerHTML = snapshot.spectators
+  const board = document.getElementById('game-board')
+  if (snapshot.game_over && !board.classList.contains('game-over')) {
+    game_over()
+  }
+  eventLog.innerHTML +=
+    '<p class="mine"><b class="title">Some events were missed while you were away.</b></p>'
+}
+
 function new_game() {
   window.location.href = '/'
 }
 
+function rematch() {
+  ws.send(
+    JSON.stringify({
+      type: 'rematch',
+      user_id,
+    }),
+  )
+  const btn = document.getElementById('rematch-button')
+  btn.setAttribute('disabled', true)
+  btn.innerHTML = 'Waiting for the other player...'
+}
+
 let hasPeerConnection = () => {}
 /**
  * @type {RTCPeerConnection | undefined}
@@ -54,10 +161,6 @@ let remoteAudio
  * @type {HTMLElement}
  */
 let callButton
-/**
- * @type {HTMLElement}
- */
-let eventLog
 /**
  * @type {(() => void) | undefined}
  */
@@ -70,22 +173,11 @@ let connected = false
  * @returns {Promise<RTCConfiguration>}
  */
 async function rtcConfig() {
-  const stunsRes = await fetch(
-    'https://raw.githubusercontent.com/pradt2/always-online-stun/master/valid_hosts.txt',
-  )
-  const stuns = new TextDecoder()
-    .decode(await stunsRes.arrayBuffer())
-    .split('\n')
-    .map((s) => s.trim())
-    .filter((s) => !!s)
-    .map((s) => `stun:${s}`)
-  return {
-    iceServers: [
-      {
-        urls: stuns,
-      },
-    ],
-  }
+  // the server hands out its configured STUN servers, and TURN credentials
+  // that expire with the game
+  const res = await fetch('./ice-config')
+  if (res.status !== 200) throw new Error(`failed to fetch ice config: ${res.status}`)
+  return await res.json()
 }
 
 /**
@@ -98,92 +190,160 @@ function load() {
   localAudio = document.getElementById('local-audio')
   remoteAudio = document.getElementById('remote-audio')
   callButton = document.getElementById('call-button')
+  if (document.getElementById('game-board').classList.contains('game-over')) {
+    document.getElementById('guess-button').setAttribute('disabled', true)
+  }
 
-  ws = new WebSocket('./ws')
-  ws.onmessage = (ev) => {
-    const event = JSON.parse(ev.data)
-    switch (event.type) {
-      case 'connected': {
-        eventLog.innerHTML += `<p class="theirs"><b class="title">The other player has connected.</b></p>`
-        connected = true
-        callButton.removeAttribute('disabled')
-        break
-      }
-      case 'disconnected': {
-        eventLog.innerHTML += `<p class="theirs"><b class="title">The other player has disconnected.</b></p>`
-        endCall().catch((e) => console.error(e))
-        connected = false
-        callButton.setAttribute('disabled', true)
-        break
-      }
-      case 'correct': {
-        eventLog.innerHTML += `<p class="theirs"><b class="title">The other player <span style="color: green">correctly</span> guessed your character in ${event.tries} ${event.tries === 1 ? 'try' : 'tries'}!</b></p>`
-        break
-      }
-      case 'incorrect': {
-        eventLog.innerHTML += `<p class="theirs"><b class="title">The other player <span style="color: red">incorrectly</span> guessed your character.</b></p>`
-        break
+  connect()
+}
+
+let reconnecting = false
+
+/**
+ * Opens the websocket, picking up after the last event we saw.
+ */
+function connect() {
+  ws = open_events(`./ws?since=${seq}`, connect, handle_event)
+  ws.onopen = () => {
+    if (reconnecting) {
+      eventLog.innerHTML += '<p class="mine"><b class="title">Reconnected.</b></p>'
+    }
+    reconnecting = false
+  }
+  ws.onclose = (ev) => {
+    if (ev.code === 1000) return
+    if (!reconnecting) {
+      eventLog.innerHTML +=
+        '<p class="mine"><b class="title" style="color: red">Lost connection, reconnecting...</b></p>'
+    }
+    reconnecting = true
+  }
+}
+
+function handle_event(event) {
+  switch (event.type) {
+    case 'connected': {
+      eventLog.innerHTML += `<p class="theirs"><b class="title">${player_label(event.seat)} has connected.</b></p>`
+      const invite = document.getElementById(`invite-${event.seat}`)
+      if (invite) {
+        invite.innerHTML = `<b>Player ${event.seat + 1}</b> has joined.`
       }
-      case 'message': {
-        eventLog.innerHTML += `<p class="theirs"><b class="title">Them: </b>${event.content}</p>`
-        break
+      connected = true
+      callButton.removeAttribute('disabled')
+      break
+    }
+    case 'disconnected': {
+      eventLog.innerHTML += `<p class="theirs"><b class="title">${player_label(event.seat)} has disconnected.</b></p>`
+      endCall().catch((e) => console.error(e))
+      connected = false
+      callButton.setAttribute('disabled', true)
+      break
+    }
+    case 'answer': {
+      if (
+        event.seat === my_seat &&
+        document.getElementById('auto-eliminate').checked
+      ) {
+        eliminate(event.eliminate, true)
       }
-      case 'call': {
-        switch (event.event.type) {
-          case 'offer': {
-            if (confirm('You are receiving a call! Accept?')) {
-              startCall(event.event.offer).catch((e) => {
-                console.error(e)
-                return endCall().catch(console.error)
-              })
-            } else {
-              ws.send(
-                JSON.stringify({
-                  type: 'call',
-                  user_id,
-                  event: { type: 'reject' },
-                }),
-              )
-            }
-            break
-          }
-          case 'answer': {
-            getPeerConnection().then((p) =>
-              p
-                .setRemoteDescription(
-                  new RTCSessionDescription(event.event.answer),
-                )
-                .catch((e) => {
-                  console.error(e)
-                  return endCall().catch(console.error)
-                }),
-            )
-            break
-          }
-          case 'candidate': {
-            getPeerConnection().then((p) =>
-              p
-                .addIceCandidate(new RTCIceCandidate(event.event.candidate))
-                .catch((e) => {
-                  console.error(e)
-                  return endCall().catch(console.error)
-                }),
+      break
+    }
+    case 'rematch': {
+      document.getElementById('rematch-button').innerHTML = 'Accept Rematch'
+      break
+    }
+    case 'restart': {
+      window.location.reload()
+      break
+    }
+    case 'turn': {
+      const mine = event.seat === my_seat
+      document.getElementById('turn-status').innerHTML = turn_status(
+        event.seat,
+      )
+      eventLog.innerHTML += mine
+        ? `<p class="mine"><b class="title">It is your turn.</b></p>`
+        : `<p class="theirs"><b class="title">It is ${num_players === 2 ? "the other player's" : `${player_label(event.seat)}'s`} turn.</b></p>`
+      break
+    }
+    case 'game-over': {
+      game_over()
+      break
+    }
+    case 'eliminate': {
+      // from another tab
+      show_eliminated(event.tiles, event.eliminated)
+      break
+    }
+    case 'spectators': {
+      document.getElementById('spectator-count').innerHTML = event.count
+      break
+    }
+    case 'error': {
+      eventLog.innerHTML += `<p class="mine"><b class="title" style="color: red">${escape_html(event.message)}</b></p>`
+      break
+    }
+    case 'react': {
+      add_reaction(event.message, event.emoji)
+      break
+    }
+    case 'call': {
+      switch (event.event.type) {
+        case 'offer': {
+          if (confirm('You are receiving a call! Accept?')) {
+            startCall(event.event.offer).catch((e) => {
+              console.error(e)
+              return endCall().catch(console.error)
+            })
+          } else {
+            ws.send(
+              JSON.stringify({
+                type: 'call',
+                user_id,
+                event: { type: 'reject' },
+              }),
             )
-            break
-          }
-          case 'reject': {
-            endCall(false).catch(console.error)
-            break
           }
+          break
+        }
+        case 'answer': {
+          getPeerConnection().then((p) =>
+            p
+              .setRemoteDescription(
+                new RTCSessionDescription(event.event.answer),
+              )
+              .catch((e) => {
+                console.error(e)
+                return endCall().catch(console.error)
+              }),
+          )
+          break
+        }
+        case 'candidate': {
+          getPeerConnection().then((p) =>
+            p
+              .addIceCandidate(new RTCIceCandidate(event.event.candidate))
+              .catch((e) => {
+                console.error(e)
+                return endCall().catch(console.error)
+              }),
+          )
+          break
+        }
+        case 'reject': {
+          endCall(false).catch(console.error)
+          break
         }
-        break
       }
+      break
     }
   }
 }
 
 function handle_click(id) {
-  if (guessing) {
+  if (document.getElementById('game-board').classList.contains('game-over')) {
+    return
+  } else if (guessing) {
     let [row, col] = id.split('-')[1].split('_')
     console.log('guessing', row, col)
     fetch(`./guess?row=${row}&col=${col}`, {
@@ -206,15 +366,46 @@ function handle_click(id) {
         } else {
           console.error('unexpected response', json)
         }
+      } else if (res.status === 409) {
+        const json = await res.json()
+        eventLog.innerHTML += `<p class="mine"><b class="title" style="color: red">${escape_html(json.error)}</b></p>`
       } else {
         console.error(res)
       }
     })
   } else {
-    document.getElementById(id).classList.toggle('blackout')
+    const [row, col] = id.split('-')[1].split('_').map(Number)
+    eliminate(
+      [[row, col]],
+      !document.getElementById(id).classList.contains('blackout'),
+    )
   }
 }
 
+function show_eliminated(tiles, eliminated) {
+  for (const [row, col] of tiles) {
+    document
+      .getElementById(`idx-${row}_${col}`)
+      .classList.toggle('blackout', eliminated)
+  }
+}
+
+/**
+ * Rules out `tiles`, or brings them back, and saves that on the server so it
+ * survives a refresh.
+ */
+function eliminate(tiles, eliminated) {
+  show_eliminated(tiles, eliminated)
+  ws.send(
+    JSON.stringify({
+      type: 'eliminate',
+      user_id,
+      tiles,
+      eliminated,
+    }),
+  )
+}
+
 let callState = null
 
 /**
@@ -318,16 +509,11 @@ async function endCall(reject = true) {
   if (connected) callButton.removeAttribute('disabled')
 }
 
-const user_id = document.cookie
-  .split(';')
-  .map((s) => s.trim())
-  .find((s) => s.startsWith('user_id='))
-  .split('=')[1]
-
 function send_message() {
   const messagebar = document.getElementById('messagebar')
-  const eventLog = document.getElementById('event-log')
   const message = messagebar.value
+  if (!message.trim()) return
+  // shown once the server sends it back, so we only ever render sanitized html
   ws.send(
     JSON.stringify({
       type: 'message',
@@ -335,10 +521,53 @@ function send_message() {
       content: message,
     }),
   )
-  eventLog.innerHTML += `<p class="mine"><b class="title">You: </b>${message}</p>`
   messagebar.value = ''
 }
 
+function quick_reply(reply) {
+  ws.send(
+    JSON.stringify({
+      type: 'quick-reply',
+      user_id,
+      reply,
+    }),
+  )
+}
+
+function react(message, emoji) {
+  ws.send(
+    JSON.stringify({
+      type: 'react',
+      user_id,
+      message,
+      emoji,
+    }),
+  )
+}
+
+function end_turn() {
+  ws.send(
+    JSON.stringify({
+      type: 'end-turn',
+      user_id,
+    }),
+  )
+}
+
+function ask() {
+  const select = document.getElementById('ask-select')
+  const option = select.options[select.selectedIndex]
+  if (!option) return
+  ws.send(
+    JSON.stringify({
+      type: 'ask',
+      user_id,
+      attribute: option.dataset.attribute,
+      value: option.dataset.value,
+    }),
+  )
+}
+
 function call() {
   if (connected) {
     if (callState === null) {
@@ -1,58 +1,161 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use anyhow::anyhow;
-use axum::body::Body;
+use axum::body::{Body, Bytes};
+use axum::extract::multipart::MultipartError;
 use axum::extract::ws::{CloseFrame, Message};
 use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, WebSocketUpgrade};
 use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::{any, get, post};
+use axum::Extension;
+use axum::Json;
 use axum::Router;
 use rand::random;
+use rand::seq::{IndexedRandom, SliceRandom};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio::sync::broadcast;
 
-use crate::pack::{CharacterCache, CharacterSet};
-use crate::utils::{SyncMutex, TimedResource};
+use crate::blobs::BlobStore;
+use crate::event_log::{EventLog, LogEvent};
+use crate::ice::IceConfig;
+use crate::images::ImageConfig;
+use crate::pack::{
+    CharacterSet, ImageSize, LibraryPack, NotEnoughImages, PackLimits, PackReport, PackSource,
+    StoreFailed,
+};
+use crate::session::{Player, Session, SessionKey, SessionRejection};
+use crate::storage::{DirStorage, GameSnapshot, Storage};
+use crate::utils::{escape_html, SyncMutex, TimedResource};
 
+mod api;
+mod blobs;
+mod chat;
+mod event_log;
+mod ice;
+mod images;
 mod pack;
+mod session;
+mod storage;
 mod utils;
 
-const NUM_ROWS: usize = 4;
-const NUM_COLS: usize = 6;
-const NUM_CHARS: usize = NUM_ROWS * NUM_COLS;
-const MAX_CHARACTER_PACK_CACHE_SIZE: usize = 1024 * 1024 * 1024;
+const DEFAULT_ROWS: usize = 4;
+const DEFAULT_COLS: usize = 6;
+const MIN_BOARD_DIM: usize = 2;
+const MAX_ROWS: usize = 8;
+const MAX_COLS: usize = 10;
+const MIN_PLAYERS: usize = 2;
+const MAX_PLAYERS: usize = 8;
+const MAX_BLOB_STORE_SIZE: usize = 1024 * 1024 * 1024;
+const MAX_PACK_NAME_LEN: usize = 100;
+const GAME_LIFETIME: Duration = Duration::from_secs(60 * 60 * 4);
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+/// How many events each game remembers for clients that reconnect.
+const HISTORY_LEN: usize = 100;
 
+/// Something that happens in a game. Players' ids are read from clients but
+/// never sent back out, since they identify sessions; [`SeatedEvent`] says
+/// which seat an event is about instead.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "kebab-case")]
 enum GameEvent {
     Connected {
+        #[serde(skip_serializing)]
         user_id: u64,
     },
     Disconnected {
+        #[serde(skip_serializing)]
         user_id: u64,
     },
     Correct {
+        #[serde(skip_serializing)]
         user_id: u64,
         tries: usize,
     },
     Incorrect {
+        #[serde(skip_serializing)]
         user_id: u64,
     },
     Message {
-        #[serde(deserialize_with = "utils::deserialize_bigint")]
+        #[serde(skip_serializing, deserialize_with = "utils::deserialize_bigint")]
         user_id: u64,
         content: String,
     },
+    QuickReply {
+        #[serde(skip_serializing, deserialize_with = "utils::deserialize_bigint")]
+        user_id: u64,
+        reply: chat::QuickReply,
+    },
+    /// an emoji reaction to the chat message sent as event `message`
+    React {
+        #[serde(skip_serializing, deserialize_with = "utils::deserialize_bigint")]
+        user_id: u64,
+        message: u64,
+        emoji: String,
+    },
     Call {
-        #[serde(deserialize_with = "utils::deserialize_bigint")]
+        #[serde(skip_serializing, deserialize_with = "utils::deserialize_bigint")]
         user_id: u64,
         event: CallEvent,
     },
+    Ask {
+        #[serde(skip_serializing, deserialize_with = "utils::deserialize_bigint")]
+        user_id: u64,
+        attribute: String,
+        value: String,
+    },
+    Answer {
+        #[serde(skip_serializing)]
+        user_id: u64,
+        attribute: String,
+        value: String,
+        answer: bool,
+        /// tiles ruled out for the asker by this answer
+        eliminate: Vec<(usize, usize)>,
+    },
+    Rematch {
+        #[serde(skip_serializing, deserialize_with = "utils::deserialize_bigint")]
+        user_id: u64,
+    },
+    Restart {
+        #[serde(skip_serializing)]
+        user_id: u64,
+    },
+    EndTurn {
+        #[serde(skip_serializing, deserialize_with = "utils::deserialize_bigint")]
+        user_id: u64,
+    },
+    Turn {
+        #[serde(skip_serializing)]
+        user_id: u64,
+    },
+    /// marks tiles the player has ruled out, or brings them back
+    Eliminate {
+        #[serde(skip_serializing, deserialize_with = "utils::deserialize_bigint")]
+        user_id: u64,
+        tiles: Vec<(usize, usize)>,
+        eliminated: bool,
+    },
+    GameOver {
+        #[serde(skip_serializing)]
+        user_id: u64,
+        /// the seat of the winner, or `None` if the game was a draw
+        winner: Option<usize>,
+    },
+    Spectators {
+        count: usize,
+    },
+    /// sent only to the user whose event was rejected
+    Error {
+        #[serde(skip_serializing)]
+        user_id: u64,
+        message: String,
+    },
 }
 impl GameEvent {
     fn user_id(&self) -> u64 {
@@ -62,26 +165,176 @@ impl GameEvent {
             Self::Correct { user_id, .. } => *user_id,
             Self::Incorrect { user_id } => *user_id,
             Self::Message { user_id, .. } => *user_id,
+            Self::QuickReply { user_id, .. } => *user_id,
+            Self::React { user_id, .. } => *user_id,
             Self::Call { user_id, .. } => *user_id,
+            Self::Ask { user_id, .. } => *user_id,
+            Self::Answer { user_id, .. } => *user_id,
+            Self::Rematch { user_id } => *user_id,
+            Self::Restart { user_id } => *user_id,
+            Self::EndTurn { user_id } => *user_id,
+            Self::Turn { user_id } => *user_id,
+            Self::Eliminate { user_id, .. } => *user_id,
+            Self::GameOver { user_id, .. } => *user_id,
+            // not caused by any player
+            Self::Spectators { .. } => 0,
+            Self::Error { user_id, .. } => *user_id,
+        }
+    }
+    /// whether the event should also be sent back to the user who caused it
+    fn echo(&self) -> bool {
+        matches!(
+            self,
+            Self::Message { .. }
+                | Self::QuickReply { .. }
+                | Self::React { .. }
+                | Self::Answer { .. }
+                | Self::Restart { .. }
+                | Self::Turn { .. }
+                | Self::Eliminate { .. }
+                | Self::GameOver { .. }
+                | Self::Spectators { .. }
+        )
+    }
+    /// whether spectators should see the event
+    fn public(&self) -> bool {
+        !matches!(self, Self::Call { .. } | Self::Error { .. })
+    }
+    /// whether the event should be sent to player `viewer`, or to spectators
+    /// if `None`
+    fn visible_to(&self, viewer: Option<u64>) -> bool {
+        match (self, viewer) {
+            // a player's notes are only shared with their other tabs and
+            // with spectators
+            (Self::Eliminate { user_id, .. }, Some(id)) => *user_id == id,
+            (_, Some(id)) => self.user_id() != id || self.echo(),
+            (_, None) => self.public(),
         }
     }
-    fn handle_user_event(self, user_id: u64) -> Result<Self, anyhow::Error> {
+    /// Applies an event sent by a user to the game, and broadcasts the result.
+    fn handle_user_event(self, user_id: u64, game: &mut GameState) -> Result<(), anyhow::Error> {
         if self.user_id() != user_id {
             return Err(anyhow!("event does not match user_id cookie"));
         }
-        match self {
-            Self::Message { content, .. } => Ok(Self::Message {
+        let event = match self {
+            Self::Message { content, .. } => Self::Message {
                 user_id,
-                content: markdown::to_html(&content)
-                    .trim_start_matches("<p>")
-                    .trim_end_matches("</p>")
-                    .to_owned(),
-            }),
-            Self::Call { .. } => Ok(self),
-            _ => Err(anyhow!("not a user defined event")),
-        }
+                content: chat::render_message(&content)?,
+            },
+            Self::QuickReply { .. } => self,
+            Self::React {
+                message, ref emoji, ..
+            } => {
+                let seat = game
+                    .seat(user_id)
+                    .ok_or_else(|| anyhow!("user is not a player in this game"))?;
+                if !chat::REACTIONS.contains(&emoji.as_str()) {
+                    return Err(anyhow!("that reaction is not allowed"));
+                }
+                if !game.log.is_chat(message) {
+                    return Err(anyhow!("there is no such message"));
+                }
+                if game.log.has_reacted(seat, message, emoji) {
+                    return Err(anyhow!("you have already reacted with that"));
+                }
+                self
+            }
+            Self::Call { .. } if game.players.len() > 2 => {
+                return Err(anyhow!("voice calls only work in two player games"));
+            }
+            Self::Call { .. } => self,
+            Self::Ask {
+                attribute, value, ..
+            } => {
+                game.check_can_act(user_id)?;
+                let opponent = game
+                    .opponent(user_id)
+                    .ok_or_else(|| anyhow!("user is not a player in this game"))?;
+                let has =
+                    |idx: usize| game.characters.0[idx].attributes.get(&attribute) == Some(&value);
+                let answer = has(opponent.character);
+                let eliminate = (0..game.rows)
+                    .flat_map(|row| (0..game.cols).map(move |col| (row, col)))
+                    .filter(|&(row, col)| has(row * game.cols + col) != answer)
+                    .collect();
+                game.send(Self::Answer {
+                    user_id,
+                    attribute,
+                    value,
+                    answer,
+                    eliminate,
+                });
+                game.end_turn();
+                return Ok(());
+            }
+            Self::Rematch { .. } => {
+                let player = game
+                    .player_mut(user_id)
+                    .ok_or_else(|| anyhow!("user is not a player in this game"))?;
+                player.wants_rematch = true;
+                if game.players.iter().all(|p| p.wants_rematch) {
+                    game.restart();
+                    Self::Restart { user_id }
+                } else {
+                    self
+                }
+            }
+            Self::Eliminate {
+                tiles, eliminated, ..
+            } => {
+                let tiles = tiles
+                    .into_iter()
+                    .map(|(row, col)| {
+                        game.char_idx(row, col)
+                            .map(|idx| ((row, col), idx))
+                            .ok_or_else(|| anyhow!("tile is off the board"))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let player = game
+                    .player_mut(user_id)
+                    .ok_or_else(|| anyhow!("user is not a player in this game"))?;
+                for &(_, idx) in &tiles {
+                    if eliminated {
+                        player.eliminated.insert(idx);
+                    } else {
+                        player.eliminated.remove(&idx);
+                    }
+                }
+                Self::Eliminate {
+                    user_id,
+                    tiles: tiles.into_iter().map(|(tile, _)| tile).collect(),
+                    eliminated,
+                }
+            }
+            Self::EndTurn { .. } => {
+                game.check_can_act(user_id)?;
+                if game.turn != Some(user_id) {
+                    return Err(anyhow!("it is not your turn"));
+                }
+                game.end_turn();
+                return Ok(());
+            }
+            _ => return Err(anyhow!("not a user defined event")),
+        };
+        game.send(event);
+        Ok(())
     }
 }
+/// A [`GameEvent`] as sent over a websocket, since javascript can't tell
+/// players apart by their u64 ids.
+#[derive(Serialize)]
+struct SeatedEvent<'a> {
+    /// the position of the event in the game's history, if it was broadcast
+    seq: Option<u64>,
+    /// the seat of the player the event is about
+    seat: Option<usize>,
+    /// the html line the event adds to the sidebar, if it is logged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log: Option<String>,
+    #[serde(flatten)]
+    event: &'a GameEvent,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "kebab-case")]
@@ -92,104 +345,1182 @@ enum CallEvent {
     Reject {},
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct GameState {
+    rows: usize,
+    cols: usize,
+    #[serde(skip)]
     characters: CharacterSet,
-    events: broadcast::Sender<GameEvent>,
-    p0: PlayerState,
-    p1: PlayerState,
+    #[serde(skip, default = "GameState::event_channel")]
+    events: broadcast::Sender<(u64, GameEvent)>,
+    /// the sequence number of the last event sent
+    #[serde(default)]
+    seq: u64,
+    /// the most recent events, so clients that reconnect can catch up
+    #[serde(skip)]
+    history: VecDeque<(u64, GameEvent)>,
+    /// how many times the game has been restarted
+    #[serde(default)]
+    round: u64,
+    /// chat and game events, kept for players who refresh or join late
+    #[serde(default)]
+    log: EventLog,
+    /// in seat order, with each player guessing the character of the next
+    players: Vec<PlayerState>,
+    /// the player whose turn it is, if playing turn based
+    #[serde(default)]
+    turn: Option<u64>,
+    #[serde(default)]
+    scoring: Scoring,
+    /// wrong guesses allowed before a player loses
+    #[serde(default)]
+    max_wrong: Option<usize>,
+    #[serde(default)]
+    outcome: Option<Outcome>,
+    /// secret part of the spectator link
+    #[serde(default = "random")]
+    spectator_key: u64,
+    #[serde(skip)]
+    spectators: usize,
 }
 impl GameState {
-    pub fn claim(&mut self, id: u64) -> bool {
-        if self.p0.id == id {
-            self.p0.claimed = true;
-            true
-        } else if self.p1.id == id {
-            self.p1.claimed = true;
-            true
+    /// Subscribers that fall behind by less than the history can still be
+    /// caught up from it, so the channel holds as many events.
+    fn event_channel() -> broadcast::Sender<(u64, GameEvent)> {
+        broadcast::channel(HISTORY_LEN).0
+    }
+    /// Broadcasts an event to everyone in the game, remembering it for
+    /// clients that reconnect.
+    fn send(&mut self, event: GameEvent) {
+        self.seq += 1;
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back((self.seq, event.clone()));
+        if let Some(entry) = self.log_event(&event) {
+            self.log.push(self.seq, entry);
+        }
+        let _ = self.events.send((self.seq, event));
+    }
+    /// How `event` is kept in the log, if it is worth keeping.
+    fn log_event(&self, event: &GameEvent) -> Option<LogEvent> {
+        let seat = self.seat(event.user_id());
+        Some(match event {
+            GameEvent::Message { content, .. } => LogEvent::Message {
+                seat: seat?,
+                content: content.clone(),
+            },
+            GameEvent::QuickReply { reply, .. } => LogEvent::QuickReply {
+                seat: seat?,
+                reply: *reply,
+            },
+            GameEvent::React { message, emoji, .. } => LogEvent::React {
+                seat: seat?,
+                message: *message,
+                emoji: emoji.clone(),
+            },
+            GameEvent::Answer {
+                attribute,
+                value,
+                answer,
+                ..
+            } => LogEvent::Answer {
+                seat: seat?,
+                attribute: attribute.clone(),
+                value: value.clone(),
+                answer: *answer,
+            },
+            GameEvent::Correct { tries, .. } => LogEvent::Correct {
+                seat: seat?,
+                tries: *tries,
+            },
+            GameEvent::Incorrect { .. } => LogEvent::Incorrect { seat: seat? },
+            GameEvent::Rematch { .. } => LogEvent::Rematch { seat: seat? },
+            GameEvent::Restart { .. } => LogEvent::Restart,
+            GameEvent::GameOver { winner, .. } => LogEvent::GameOver { winner: *winner },
+            _ => return None,
+        })
+    }
+    /// Serializes an event for the websocket of player `viewer`, or of a
+    /// spectator if `None`.
+    fn ws_message(
+        &self,
+        seq: Option<u64>,
+        event: &GameEvent,
+        viewer: Option<u64>,
+    ) -> Result<Message, anyhow::Error> {
+        let viewer = viewer.and_then(|id| self.seat(id));
+        Ok(Message::Text(
+            serde_json::to_string(&SeatedEvent {
+                seq,
+                seat: self.seat(event.user_id()),
+                log: seq.zip(self.log_event(event)).and_then(|(seq, entry)| {
+                    entry.html(seq, viewer, self.players.len())
+                }),
+                event,
+            })?
+            .into(),
+        ))
+    }
+    /// Everything player `viewer`, or a spectator if `None`, needs to know to
+    /// catch up without replaying any events.
+    fn snapshot(&self, viewer: Option<u64>) -> Snapshot {
+        Snapshot {
+            seq: self.seq,
+            round: self.round,
+            turn: self.turn.and_then(|turn| self.seat(turn)),
+            game_over: self.outcome.is_some(),
+            players: self
+                .players
+                .iter()
+                .enumerate()
+                .map(|(seat, p)| api::PlayerView::new(seat, p))
+                .collect(),
+            guesses: viewer
+                .and_then(|id| Some((self.player(id)?, self.opponent(id)?)))
+                .map(|(player, opponent)| {
+                    player
+                        .guesses
+                        .iter()
+                        .map(|&idx| GuessView {
+                            row: idx / self.cols,
+                            col: idx % self.cols,
+                            correct: idx == opponent.character,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            eliminated: viewer
+                .and_then(|id| self.player(id))
+                .map(|p| {
+                    p.eliminated
+                        .iter()
+                        .map(|&idx| (idx / self.cols, idx % self.cols))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            spectators: self.spectators,
+        }
+    }
+    /// The messages a client that has seen every event up to `since` needs to
+    /// catch up: the events it missed if they are all still in the history,
+    /// or a snapshot otherwise.
+    fn catch_up(
+        &self,
+        since: Option<u64>,
+        viewer: Option<u64>,
+    ) -> Result<Vec<Message>, anyhow::Error> {
+        let first = self.history.front().map_or(self.seq + 1, |(seq, _)| *seq);
+        match since {
+            Some(since) if since <= self.seq && since + 1 >= first => self
+                .history
+                .iter()
+                // stale call signalling would only confuse the client
+                .filter(|(seq, e)| {
+                    *seq > since && e.visible_to(viewer) && !matches!(e, GameEvent::Call { .. })
+                })
+                .map(|(seq, e)| self.ws_message(Some(*seq), e, viewer))
+                .collect(),
+            _ => Ok(vec![Message::Text(
+                serde_json::to_string(&self.snapshot(viewer))?.into(),
+            )]),
+        }
+    }
+    pub fn char_idx(&self, row: usize, col: usize) -> Option<usize> {
+        if row < self.rows && col < self.cols {
+            Some(row * self.cols + col)
         } else {
-            false
+            None
         }
     }
-    pub fn set_connected(&mut self, id: u64, connected: bool) -> Option<u64> {
-        let res = if self.p0.id == id {
-            self.p0.connected = connected;
-            if self.p1.connected {
-                Some(self.p1.id)
-            } else {
-                None
+    pub fn player(&self, id: u64) -> Option<&PlayerState> {
+        self.players.iter().find(|p| p.id == id)
+    }
+    /// The index of the player's seat, counting from 0.
+    pub fn seat(&self, id: u64) -> Option<usize> {
+        self.players.iter().position(|p| p.id == id)
+    }
+    /// How spectators see a player, since they don't know anyone's name.
+    pub fn label(&self, id: u64) -> String {
+        match self.seat(id) {
+            Some(seat) => format!("Player {}", seat + 1),
+            None => "Someone".to_owned(),
+        }
+    }
+    pub fn player_mut(&mut self, id: u64) -> Option<&mut PlayerState> {
+        self.players.iter_mut().find(|p| p.id == id)
+    }
+    /// The player whose character `id` is trying to guess.
+    pub fn opponent(&self, id: u64) -> Option<&PlayerState> {
+        let seat = self.seat(id)?;
+        Some(&self.players[(seat + 1) % self.players.len()])
+    }
+    /// Checks whether `id` may ask a question or guess right now.
+    pub fn check_can_act(&self, id: u64) -> Result<(), anyhow::Error> {
+        let player = self
+            .player(id)
+            .ok_or_else(|| anyhow!("user is not a player in this game"))?;
+        if self.outcome.is_some() {
+            return Err(anyhow!("the game is over"));
+        }
+        if player.finished(self.max_wrong) {
+            return Err(anyhow!("you have no guesses left"));
+        }
+        if self.turn.is_some_and(|turn| turn != id) {
+            return Err(anyhow!("it is not your turn"));
+        }
+        Ok(())
+    }
+    /// Passes the turn to the next player who still has guesses left, if
+    /// playing turn based.
+    pub fn end_turn(&mut self) {
+        if self.outcome.is_some() {
+            return;
+        }
+        let Some(seat) = self.turn.and_then(|turn| self.seat(turn)) else {
+            return;
+        };
+        let Some(next) = (1..self.players.len())
+            .map(|i| &self.players[(seat + i) % self.players.len()])
+            .find(|p| !p.finished(self.max_wrong))
+            .map(|p| p.id)
+        else {
+            return;
+        };
+        self.turn = Some(next);
+        self.send(GameEvent::Turn { user_id: next });
+    }
+    /// Records a guess by `id` at the character at `char_idx`, returning
+    /// whether it was correct.
+    pub fn guess(&mut self, id: u64, char_idx: usize) -> Result<bool, anyhow::Error> {
+        self.check_can_act(id)?;
+        let correct = self
+            .opponent(id)
+            .is_some_and(|other| other.character == char_idx);
+        let player_data = self
+            .player_mut(id)
+            .ok_or_else(|| anyhow!("user is not a player in this game"))?;
+        if correct {
+            player_data.correct = true;
+        } else {
+            player_data.incorrect_count += 1;
+        }
+        player_data.guesses.push(char_idx);
+        player_data.eliminated.remove(&char_idx);
+        let tries = player_data.incorrect_count + 1;
+        self.send(if correct {
+            GameEvent::Correct { user_id: id, tries }
+        } else {
+            GameEvent::Incorrect { user_id: id }
+        });
+        self.check_outcome(id);
+        self.end_turn();
+        Ok(correct)
+    }
+    /// Ends the game if the last guess, made by `id`, decided it.
+    fn check_outcome(&mut self, id: u64) {
+        if self.outcome.is_some() {
+            return;
+        }
+        let winner = match self.scoring {
+            Scoring::FirstCorrect => {
+                let mut left = self
+                    .players
+                    .iter()
+                    .filter(|p| self.max_wrong.is_none_or(|m| p.incorrect_count < m));
+                if let Some(p) = self.players.iter().find(|p| p.correct) {
+                    Some(Some(p.id))
+                } else {
+                    // everyone else has run out of guesses
+                    match (left.next(), left.next()) {
+                        (Some(p), None) => Some(Some(p.id)),
+                        (None, _) => Some(None),
+                        _ => None,
+                    }
+                }
             }
-        } else if self.p1.id == id {
-            self.p1.connected = connected;
-            if self.p0.connected {
-                Some(self.p0.id)
-            } else {
-                None
+            Scoring::FewestTries => {
+                if !self.players.iter().all(|p| p.finished(self.max_wrong)) {
+                    None
+                } else {
+                    let best = self
+                        .players
+                        .iter()
+                        .filter(|p| p.correct)
+                        .map(|p| p.incorrect_count)
+                        .min();
+                    let mut best = self
+                        .players
+                        .iter()
+                        .filter(|p| p.correct && Some(p.incorrect_count) == best);
+                    // a tie for the fewest tries is a draw
+                    Some(match (best.next(), best.next()) {
+                        (Some(p), None) => Some(p.id),
+                        _ => None,
+                    })
+                }
             }
+        };
+        if let Some(winner) = winner {
+            self.outcome = Some(Outcome { winner });
+            self.send(GameEvent::GameOver {
+                user_id: id,
+                winner: winner.and_then(|winner| self.seat(winner)),
+            });
+        }
+    }
+    /// Renders the board, marking the tiles player `viewer` has guessed or
+    /// ruled out.
+    pub fn board(&self, viewer: Option<u64>) -> String {
+        let player = viewer.and_then(|id| self.player(id));
+        let target = viewer.and_then(|id| self.opponent(id));
+        format!(
+            "<table>{}</table>",
+            (0..self.rows)
+                .map(|row| format!(
+                    "<tr>{}</tr>",
+                    (0..self.cols)
+                        .map(|col| {
+                            let idx = row * self.cols + col;
+                            let character = &self.characters.0[idx];
+                            let class = match (player, target) {
+                                (Some(player), Some(target)) if player.guesses.contains(&idx) => {
+                                    if target.character == idx {
+                                        "correct"
+                                    } else {
+                                        "incorrect"
+                                    }
+                                }
+                                (Some(player), _) if player.eliminated.contains(&idx) => "blackout",
+                                _ => "",
+                            };
+                            format!(
+                                include_str!("./game-cell.html.template"),
+                                row = row,
+                                col = col,
+                                class = class,
+                                caption = escape_html(character.name.as_deref().unwrap_or("")),
+                                description = escape_html(&character.description()),
+                            )
+                        })
+                        .collect::<String>()
+                ))
+                .collect::<String>()
+        )
+    }
+    /// Renders the results panel if the game is over, from the point of view
+    /// of player `viewer`, or of a spectator if `None`.
+    pub fn results(&self, viewer: Option<u64>) -> Option<String> {
+        let outcome = self.outcome?;
+        if viewer.is_some_and(|id| self.player(id).is_none()) {
+            return None;
+        }
+        let query = if viewer.is_none() {
+            format!("?key={}", self.spectator_key)
         } else {
-            None
+            String::new()
         };
-        self.events
-            .send(if connected {
-                GameEvent::Connected { user_id: id }
-            } else {
-                GameEvent::Disconnected { user_id: id }
+        Some(format!(
+            include_str!("./results.html.template"),
+            query = query,
+            headline = match (outcome.winner, viewer) {
+                (Some(winner), Some(id)) if winner == id => "You won!".to_owned(),
+                (Some(winner), _) => format!("{} won!", self.label(winner)),
+                (None, _) => "It's a draw.".to_owned(),
+            },
+            players = self
+                .players
+                .iter()
+                .map(|p| {
+                    let (title, subject) = if Some(p.id) == viewer {
+                        ("Your character".to_owned(), "You".to_owned())
+                    } else {
+                        let label = self.label(p.id);
+                        (format!("{label}'s character"), label)
+                    };
+                    format!(
+                        include_str!("./results-player.html.template"),
+                        row = p.character / self.cols,
+                        col = p.character % self.cols,
+                        title = title,
+                        name = self.characters.0[p.character]
+                            .name
+                            .as_deref()
+                            .map(escape_html)
+                            .unwrap_or_else(|| "(unnamed)".to_owned()),
+                        subject = subject,
+                        summary = p.summary(),
+                    )
+                })
+                .collect::<String>(),
+        ))
+    }
+    /// Starts a new round with the same players and characters, reshuffling
+    /// the board and picking new secret characters.
+    pub fn restart(&mut self) {
+        self.characters.0.shuffle(&mut rand::rng());
+        let num_chars = self.characters.0.len();
+        for player in &mut self.players {
+            player.character = rand::random_range(0..num_chars);
+            player.incorrect_count = 0;
+            player.correct = false;
+            player.wants_rematch = false;
+            player.guesses.clear();
+            player.eliminated.clear();
+        }
+        self.outcome = None;
+        self.round += 1;
+        if self.turn.is_some() {
+            self.turn = self.players.choose(&mut rand::rng()).map(|p| p.id);
+        }
+    }
+    /// Whether `id` created the game, and so may manage its invites.
+    pub fn is_host(&self, id: u64) -> bool {
+        self.players.first().is_some_and(|p| p.id == id)
+    }
+    /// Claims the seat that `invite` was issued for, returning its index and
+    /// the id of the player sitting there.
+    pub fn claim_invite(&mut self, invite: u64) -> Option<(usize, u64)> {
+        let (seat, player) = self
+            .players
+            .iter_mut()
+            .enumerate()
+            .find(|(_, p)| !p.claimed && p.invite == invite)?;
+        player.claimed = true;
+        Some((seat, player.id))
+    }
+    /// Issues a new invite for an empty seat, so the old one stops working.
+    pub fn reissue_invite(&mut self, seat: usize) -> Result<u64, anyhow::Error> {
+        let player = self
+            .players
+            .get_mut(seat)
+            .ok_or_else(|| anyhow!("there is no seat {seat}"))?;
+        if player.claimed {
+            return Err(anyhow!("someone is already sitting in that seat"));
+        }
+        player.invite = random();
+        Ok(player.invite)
+    }
+    /// Renders the host's list of invite links for the other seats.
+    pub fn invites(&self, game_url: &str) -> String {
+        self.players
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(seat, p)| {
+                if p.claimed {
+                    format!(
+                        "<div class=\"invite\"><b>{}</b> has joined.</div>",
+                        self.label(p.id)
+                    )
+                } else {
+                    format!(
+                        include_str!("./invite.html.template"),
+                        seat = seat,
+                        label = self.label(p.id),
+                        link = escape_html(&format!("{game_url}?invite={}", p.invite)),
+                    )
+                }
             })
-            .ok();
-        res
+            .collect()
+    }
+    pub fn claim(&mut self, id: u64) -> bool {
+        if let Some(player) = self.player_mut(id) {
+            player.claimed = true;
+            true
+        } else {
+            false
+        }
+    }
+    pub fn set_spectating(&mut self, joined: bool) {
+        if joined {
+            self.spectators += 1;
+        } else {
+            self.spectators -= 1;
+        }
+        self.send(GameEvent::Spectators {
+            count: self.spectators,
+        });
+    }
+    /// Marks a player as connected or not, returning the other players that
+    /// are currently connected.
+    pub fn set_connected(&mut self, id: u64, connected: bool) -> Vec<u64> {
+        let Some(player) = self.player_mut(id) else {
+            return Vec::new();
+        };
+        player.connected = connected;
+        self.send(if connected {
+            GameEvent::Connected { user_id: id }
+        } else {
+            GameEvent::Disconnected { user_id: id }
+        });
+        self.players
+            .iter()
+            .filter(|p| p.id != id && p.connected)
+            .map(|p| p.id)
+            .collect()
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// How the winner is decided.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Scoring {
+    /// the first player to guess correctly wins
+    #[default]
+    FirstCorrect,
+    /// everyone guesses until they are right, and whoever took the fewest
+    /// tries wins
+    FewestTries,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Outcome {
+    /// `None` if the game was a draw
+    winner: Option<u64>,
+}
+
+/// Everything a client needs to catch up after missing events that are no
+/// longer in the history.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "sync")]
+struct Snapshot {
+    seq: u64,
+    round: u64,
+    /// the seat whose turn it is, if playing turn based
+    turn: Option<usize>,
+    game_over: bool,
+    players: Vec<api::PlayerView>,
+    /// the tiles the viewer has guessed, oldest first
+    guesses: Vec<GuessView>,
+    /// the tiles the viewer has ruled out
+    eliminated: Vec<(usize, usize)>,
+    spectators: usize,
+}
+
+#[derive(Serialize)]
+struct GuessView {
+    row: usize,
+    col: usize,
+    correct: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct PlayerState {
     id: u64,
     claimed: bool,
     character: usize,
     incorrect_count: usize,
     correct: bool,
+    #[serde(default)]
+    wants_rematch: bool,
+    #[serde(skip)]
     connected: bool,
+    /// needed to claim the seat
+    #[serde(default = "random")]
+    invite: u64,
+    /// the characters the player has guessed, oldest first
+    #[serde(default)]
+    guesses: Vec<usize>,
+    /// the characters the player has ruled out
+    #[serde(default)]
+    eliminated: BTreeSet<usize>,
 }
 impl PlayerState {
-    fn random() -> Self {
+    /// How the player's guessing went, for the results panel.
+    fn summary(&self) -> String {
+        if self.correct {
+            let tries = self.incorrect_count + 1;
+            format!(
+                "guessed correctly in {tries} {}",
+                if tries == 1 { "try" } else { "tries" }
+            )
+        } else {
+            let wrong = self.incorrect_count;
+            format!(
+                "made {wrong} wrong guess{}",
+                if wrong == 1 { "" } else { "es" }
+            )
+        }
+    }
+    /// Whether the player has either guessed correctly or run out of guesses.
+    fn finished(&self, max_wrong: Option<usize>) -> bool {
+        self.correct || max_wrong.is_some_and(|m| self.incorrect_count >= m)
+    }
+    fn random(num_chars: usize) -> Self {
         Self {
             id: random(),
             claimed: false,
-            character: rand::random_range(0..NUM_CHARS),
+            character: rand::random_range(0..num_chars),
             incorrect_count: 0,
             correct: false,
+            wants_rematch: false,
             connected: false,
+            invite: random(),
+            guesses: Vec::new(),
+            eliminated: BTreeSet::new(),
         }
     }
 }
 
+#[derive(serde::Deserialize)]
+struct InviteParams {
+    invite: Option<u64>,
+}
+
+#[derive(serde::Deserialize)]
+struct ResumeParams {
+    /// the sequence number of the last event the client saw
+    since: Option<u64>,
+}
+
+#[derive(serde::Deserialize)]
+struct ImageParams {
+    #[serde(default)]
+    size: ImageSize,
+}
+
+#[derive(serde::Deserialize)]
+struct SpectateParams {
+    key: u64,
+    /// the seat whose ruled out tiles are shown on the board
+    seat: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct ResultsParams {
+    key: Option<u64>,
+}
+
 #[derive(serde::Deserialize)]
 struct GuessParams {
     row: usize,
     col: usize,
 }
 
-#[derive(Default)]
+/// The error for new game settings that can't be read or are out of range.
+#[derive(Debug)]
+struct InvalidSettings(String);
+impl std::fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl std::error::Error for InvalidSettings {}
+
+/// The error for a `pack_id` that isn't in the library.
+#[derive(Debug)]
+struct PackNotFound;
+impl std::fmt::Display for PackNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("pack not found in library")
+    }
+}
+impl std::error::Error for PackNotFound {}
+
+/// Whether loading a pack failed because of the server rather than anything
+/// wrong with the pack or the settings.
+fn is_server_error(e: &anyhow::Error) -> bool {
+    e.is::<StoreFailed>() || e.is::<tokio::task::JoinError>()
+}
+
+/// Parses the number in the settings field `name`.
+fn parse_setting(value: &str, name: &str) -> Result<usize, InvalidSettings> {
+    let value = value.trim();
+    value
+        .parse()
+        .map_err(|_| InvalidSettings(format!("{name} must be a whole number, not {value:?}")))
+}
+
+/// Settings for a new game, from the new game form or the api.
+struct NewGame {
+    /// the files uploaded as the pack, with their names
+    uploads: Vec<(String, Bytes)>,
+    pack_id: Option<u64>,
+    rows: usize,
+    cols: usize,
+    num_players: usize,
+    turn_based: bool,
+    scoring: Scoring,
+    max_wrong: Option<usize>,
+}
+impl NewGame {
+    async fn from_multipart(multipart: &mut Multipart) -> Result<Self, anyhow::Error> {
+        let mut res = Self {
+            uploads: Vec::new(),
+            pack_id: None,
+            rows: DEFAULT_ROWS,
+            cols: DEFAULT_COLS,
+            num_players: MIN_PLAYERS,
+            turn_based: false,
+            scoring: Scoring::default(),
+            max_wrong: None,
+        };
+        while let Some(field) = multipart.next_field().await? {
+            match field.name() {
+                Some("character_pack") => {
+                    let name = field.file_name().unwrap_or_default().to_owned();
+                    let data = field.bytes().await?;
+                    if !data.is_empty() {
+                        res.uploads.push((name, data));
+                    }
+                }
+                Some("pack_id") => {
+                    let id = field.text().await?;
+                    if !id.is_empty() {
+                        res.pack_id = Some(id.parse::<u64>()?);
+                    }
+                }
+                Some("rows") => {
+                    res.rows = parse_setting(&field.text().await?, "rows")?;
+                }
+                Some("cols") => {
+                    res.cols = parse_setting(&field.text().await?, "columns")?;
+                }
+                Some("turn_based") => {
+                    res.turn_based = !field.text().await?.is_empty();
+                }
+                Some("players") => {
+                    res.num_players = parse_setting(&field.text().await?, "players")?;
+                }
+                Some("scoring") => {
+                    res.scoring = match field.text().await?.as_str() {
+                        "fewest-tries" => Scoring::FewestTries,
+                        _ => Scoring::FirstCorrect,
+                    };
+                }
+                Some("max_wrong") => {
+                    let max = field.text().await?;
+                    if !max.trim().is_empty() {
+                        res.max_wrong =
+                            Some(parse_setting(&max, "wrong guesses allowed")?).filter(|m| *m > 0);
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(res)
+    }
+
+    /// Checks the settings and picks the characters for the board, from
+    /// either the library or the uploaded pack, along with the report on the
+    /// uploaded pack.
+    async fn characters(
+        &mut self,
+        app: &SyncMutex<AppState>,
+    ) -> Result<(CharacterSet, Option<PackReport>), anyhow::Error> {
+        let (rows, cols) = (self.rows, self.cols);
+        if !(MIN_PLAYERS..=MAX_PLAYERS).contains(&self.num_players) {
+            return Err(InvalidSettings(format!(
+                "games must have between {MIN_PLAYERS} and {MAX_PLAYERS} players"
+            ))
+            .into());
+        }
+        if !(MIN_BOARD_DIM..=MAX_ROWS).contains(&rows)
+            || !(MIN_BOARD_DIM..=MAX_COLS).contains(&cols)
+        {
+            return Err(InvalidSettings(format!(
+                "board must be between {MIN_BOARD_DIM}x{MIN_BOARD_DIM} and {MAX_ROWS}x{MAX_COLS}"
+            ))
+            .into());
+        }
+        if let Some(pack_id) = self.pack_id {
+            let pack = app
+                .peek(|g| g.library.get(&pack_id).cloned())
+                .ok_or(PackNotFound)?;
+            Ok((pack.characters.choose(rows * cols)?, None))
+        } else {
+            let pack = PackSource::new(std::mem::take(&mut self.uploads))?
+                .ok_or_else(|| InvalidSettings("character pack required".to_owned()))?;
+            let (blobs, images, limits) =
+                app.peek(|g| (g.blobs.clone(), g.images.clone(), g.pack_limits.clone()));
+            let (characters, report) = tokio::task::spawn_blocking(move || {
+                CharacterSet::load(&blobs, &images, &limits, pack, rows * cols, rows * cols)
+            })
+            .await??;
+            Ok((characters, Some(report)))
+        }
+    }
+}
+
+/// Starts a new game, returning its id and the host's user id. Any game the
+/// host was already playing is destroyed.
+fn start_game(
+    app: &Arc<SyncMutex<AppState>>,
+    settings: NewGame,
+    characters: CharacterSet,
+    host: Option<u64>,
+) -> (u64, u64) {
+    let game_id: u64 = random();
+    let NewGame {
+        rows,
+        cols,
+        num_players,
+        turn_based,
+        scoring,
+        max_wrong,
+        ..
+    } = settings;
+    let mut players = (0..num_players)
+        .map(|_| PlayerState::random(rows * cols))
+        .collect::<Vec<_>>();
+    players[0].id = host.unwrap_or(players[0].id);
+    players[0].claimed = true;
+    let host_id = players[0].id;
+    let turn = turn_based
+        .then(|| players.choose(&mut rand::rng()).map(|p| p.id))
+        .flatten();
+    app.mutate(|g| {
+        g.games.retain(|_, g| {
+            if g.is_timed_out() {
+                false
+            } else if let Some(game) = g.get() {
+                !game.peek(|g| g.player(host_id).is_some())
+            // destroy the user's previous game
+            } else {
+                false
+            }
+        });
+        g.games.insert(
+            game_id,
+            TimedResource::new(
+                SyncMutex::new(GameState {
+                    rows,
+                    cols,
+                    characters,
+                    events: GameState::event_channel(),
+                    seq: 0,
+                    history: VecDeque::new(),
+                    round: 0,
+                    log: EventLog::default(),
+                    players,
+                    turn,
+                    scoring,
+                    max_wrong,
+                    outcome: None,
+                    spectator_key: random(),
+                    spectators: 0,
+                }),
+                GAME_LIFETIME,
+            ),
+        );
+        eprintln!("{} games are active", g.games.len());
+    });
+    tokio::task::spawn_blocking({
+        let app = app.clone();
+        move || persist(&app)
+    });
+    (game_id, host_id)
+}
+
 struct AppState {
     games: BTreeMap<u64, TimedResource<SyncMutex<GameState>>>,
-    cache: CharacterCache,
+    library: BTreeMap<u64, Arc<LibraryPack>>,
+    blobs: BlobStore,
+    storage: Option<Arc<dyn Storage>>,
+    session_key: SessionKey,
+    ice: IceConfig,
+    images: ImageConfig,
+    pack_limits: PackLimits,
+}
+impl AppState {
+    fn restore(&mut self, storage: &dyn Storage) -> Result<(), anyhow::Error> {
+        for id in storage.ids()? {
+            match storage.load(id, &self.blobs) {
+                Ok(snapshot) if snapshot.expires_at > SystemTime::now() => {
+                    self.games.insert(
+                        id,
+                        TimedResource::until(SyncMutex::new(snapshot.game), snapshot.expires_at),
+                    );
+                }
+                Ok(_) => storage.remove(id)?,
+                Err(e) => {
+                    eprintln!("failed to restore game {id}: {e}");
+                    eprintln!("{e:?}");
+                }
+            }
+        }
+        eprintln!("restored {} games", self.games.len());
+        for (id, pack) in storage.load_packs(&self.blobs)? {
+            self.library.insert(id, Arc::new(pack));
+        }
+        eprintln!("library has {} packs", self.library.len());
+        Ok(())
+    }
+
+    fn snapshot(&self, id: u64) -> Option<GameSnapshot> {
+        let game = self.games.get(&id)?;
+        Some(GameSnapshot {
+            expires_at: game.expires_at(),
+            game: game.get()?.peek(|g| g.clone()),
+        })
+    }
+}
+
+/// Held for the whole of [`persist`], so that a run with an older snapshot
+/// can't remove or overwrite what a newer one has just written.
+static PERSISTING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Writes every active game to storage, and removes any that have ended.
+fn persist(app: &SyncMutex<AppState>) {
+    let _persisting = PERSISTING.lock().unwrap();
+    let Some((storage, snapshots)) = app.mutate(|a| {
+        a.games.retain(|_, g| !g.is_timed_out());
+        let snapshots = a
+            .games
+            .keys()
+            .filter_map(|id| Some((*id, a.snapshot(*id)?)))
+            .collect::<BTreeMap<_, _>>();
+        Some((a.storage.clone()?, snapshots))
+    }) else {
+        return;
+    };
+    if let Err(e) = (|| {
+        for id in storage.ids()? {
+            if !snapshots.contains_key(&id) {
+                storage.remove(id)?;
+            }
+        }
+        for (id, snapshot) in &snapshots {
+            storage.save(*id, snapshot)?;
+        }
+        Ok::<_, anyhow::Error>(())
+    })() {
+        eprintln!("failed to persist games: {e}");
+        eprintln!("{e:?}");
+    }
+}
+
+/// The scheme and host the request was sent to, for links that are shared
+/// outside the browser.
+fn origin(headers: &HeaderMap) -> String {
+    let header = |name| headers.get(name).and_then(|h| h.to_str().ok());
+    format!(
+        "{}://{}",
+        header("x-forwarded-proto").unwrap_or("http"),
+        header("x-forwarded-host")
+            .or_else(|| header("host"))
+            .unwrap_or("localhost:3000"),
+    )
+}
+
+/// Whether the browser reached us over https, through a proxy that
+/// terminates it, since the server itself only speaks plain http.
+fn is_https(headers: &HeaderMap) -> bool {
+    headers
+        .get("x-forwarded-proto")
+        .is_some_and(|proto| proto == "https")
+}
+
+fn invalid_settings(e: &InvalidSettings) -> Response {
+    let mut res = StatusCode::BAD_REQUEST.into_response();
+    *res.body_mut() = Body::from(format!(
+        include_str!("./invalid_settings.html.template"),
+        error = escape_html(&e.to_string()),
+    ));
+    res
+}
+
+/// The page shown after a pack is uploaded, with the report on it and a link
+/// on to `next`.
+fn pack_loaded(title: &str, report: &PackReport, next: &str, next_label: &str) -> Response {
+    let mut res = StatusCode::OK.into_response();
+    *res.body_mut() = Body::from(format!(
+        include_str!("./pack_loaded.html.template"),
+        title = title,
+        report = report.html(),
+        next = next,
+        next_label = next_label,
+    ));
+    res.headers_mut()
+        .insert("content-type", HeaderValue::from_static("text/html"));
+    res
+}
+
+fn invalid_pack(e: anyhow::Error, num: usize) -> Response {
+    let mut res = StatusCode::INSUFFICIENT_STORAGE.into_response();
+    *res.body_mut() = Body::from(format!(
+        include_str!("./invalid_pack.html.template"),
+        error = escape_html(&e.to_string()),
+        error_dbg = serde_json::to_string(&format!("{e:?}")).unwrap(),
+        num = num,
+        report = e
+            .downcast_ref::<NotEnoughImages>()
+            .map(|NotEnoughImages(report)| report.html())
+            .unwrap_or_default(),
+    ));
+    res
+}
+
+/// The response for an upload bigger than [`PackLimits`] allows, if that's
+/// what went wrong.
+fn upload_too_large(e: &anyhow::Error) -> Option<Response> {
+    let e = e.downcast_ref::<MultipartError>()?;
+    (e.status() == StatusCode::PAYLOAD_TOO_LARGE)
+        .then(|| (e.status(), "the upload is too large").into_response())
+}
+
+async fn shutdown_signal() {
+    let mut terminate =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
 }
 
 #[tokio::main]
 
 async fn main() {
-    let games = Arc::new(SyncMutex::new(AppState::default()));
+    let data_dir = std::env::var_os("IMPOSTER_ROSTER_DATA_DIR").map(PathBuf::from);
+    let mut state = AppState {
+        games: BTreeMap::new(),
+        library: BTreeMap::new(),
+        blobs: BlobStore::open(
+            data_dir
+                .clone()
+                .unwrap_or_else(|| std::env::temp_dir().join("imposter-roster"))
+                .join("blobs"),
+            MAX_BLOB_STORE_SIZE,
+        )
+        .unwrap(),
+        storage: None,
+        session_key: SessionKey::load(data_dir.as_deref()).unwrap(),
+        ice: IceConfig::from_env().unwrap(),
+        images: ImageConfig::from_env().unwrap(),
+        pack_limits: PackLimits::from_env().unwrap(),
+    };
+    if let Some(port) = state.ice.stun_port {
+        tokio::spawn(async move {
+            if let Err(e) = ice::serve_stun(port).await {
+                eprintln!("stun server stopped: {e}");
+            }
+        });
+    }
+    if let Some(data_dir) = data_dir {
+        let storage = DirStorage::new(data_dir).unwrap();
+        state.restore(&storage).unwrap();
+        state.storage = Some(Arc::new(storage));
+    }
+    let games = Arc::new(SyncMutex::new(state));
+
+    tokio::spawn({
+        let games = games.clone();
+        async move {
+            let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let games = games.clone();
+                tokio::task::spawn_blocking(move || persist(&games))
+                    .await
+                    .ok();
+            }
+        }
+    });
 
     let app =
         Router::new()
-            .route(
-                "/",
-                get(|| async {
+            .route("/", {
+                let games = games.clone();
+                get(|| async move {
+                    let packs = games.peek(|g| {
+                        g.library
+                            .iter()
+                            .map(|(id, pack)| {
+                                format!(
+                                    "<option value=\"{id}\">{} ({} characters)</option>",
+                                    escape_html(&pack.name),
+                                    pack.characters.0.len(),
+                                )
+                            })
+                            .collect::<String>()
+                    });
                     let mut res = StatusCode::OK.into_response();
-                    *res.body_mut() = Body::from(include_str!("./index.html"));
+                    *res.body_mut() =
+                        Body::from(format!(include_str!("./index.html.template"), packs = packs));
                     res.headers_mut()
                         .insert("content-type", HeaderValue::from_static("text/html"));
                     res
-                }),
-            )
+                })
+            })
+            .route("/packs", {
+                let games = games.clone();
+                post(|mut multipart: Multipart| async move {
+                    async {
+                        let (blobs, images, limits) = games.peek(|g| (g.blobs.clone(), g.images.clone(), g.pack_limits.clone()));
+                        if blobs.referenced_size() >= MAX_BLOB_STORE_SIZE {
+                            let mut res = StatusCode::INSUFFICIENT_STORAGE.into_response();
+                            *res.body_mut() = Body::from(include_str!("./overloaded.html"));
+                            return Ok(res);
+                        }
+                        let min = MIN_BOARD_DIM * MIN_BOARD_DIM;
+                        let mut name = String::new();
+                        let mut uploads = Vec::new();
+                        while let Some(field) = multipart.next_field().await? {
+                            match field.name() {
+                                Some("character_pack") => {
+                                    let name = field.file_name().unwrap_or_default().to_owned();
+                                    let data = field.bytes().await?;
+                                    if !data.is_empty() {
+                                        uploads.push((name, data));
+                                    }
+                                }
+                                Some("name") => {
+                                    name = field.text().await?.trim().to_owned();
+                                }
+                                _ => (),
+                            }
+                        }
+                        if name.is_empty() || name.len() > MAX_PACK_NAME_LEN {
+                            return Ok(invalid_pack(
+                                anyhow!("pack name must be between 1 and {MAX_PACK_NAME_LEN} characters"),
+                                min,
+                            ));
+                        }
+                        let pack = match PackSource::new(uploads) {
+                            Ok(Some(pack)) => pack,
+                            Ok(None) => {
+                                return Ok(invalid_pack(anyhow!("character pack required"), min))
+                            }
+                            Err(e) => return Ok(invalid_pack(e, min)),
+                        };
+                        let (characters, report) = match tokio::task::spawn_blocking(move || {
+                            CharacterSet::load(&blobs, &images, &limits, pack, min, usize::MAX)
+                        })
+                        .await?
+                        {
+                            Ok(a) => a,
+                            Err(e) if is_server_error(&e) => return Err(e),
+                            Err(e) => return Ok(invalid_pack(e, min)),
+                        };
+                        let pack_id: u64 = random();
+                        let title = format!("Added {} to the library", escape_html(&name));
+                        let pack = Arc::new(LibraryPack { name, characters });
+                        let storage = games.mutate(|g| {
+                            g.library.insert(pack_id, pack.clone());
+                            g.storage.clone()
+                        });
+                        if let Some(storage) = storage {
+                            tokio::task::spawn_blocking(move || storage.save_pack(pack_id, &pack))
+                                .await??;
+                        }
+                        Ok(pack_loaded(&title, &report, "/", "Back to the start page"))
+                    }
+                    .await
+                    .map_err(|e: anyhow::Error| {
+                        if let Some(res) = upload_too_large(&e) {
+                            return res;
+                        }
+                        eprintln!("{e}");
+                        eprintln!("{e:?}");
+                        let mut res = StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                        *res.body_mut() = Body::from(include_str!("./oops.html"));
+                        res.headers_mut()
+                            .insert("content-type", HeaderValue::from_static("text/html"));
+                        res
+                    })
+                })
+            })
             .route(
                 "/icon.jpeg",
                 get(|| async {
@@ -202,79 +1533,51 @@ async fn main() {
             )
             .route("/new_game", {
                 let games = games.clone();
-                post(|headers: HeaderMap, mut multipart: Multipart| async move {
+                post(|headers: HeaderMap, session: Result<Session, SessionRejection>, mut multipart: Multipart| async move {
                     async {
-                        let game_id: u64 = random();
-                        let mut set = None;
-                        if games.mutate(|g| g.cache.size()) >= MAX_CHARACTER_PACK_CACHE_SIZE {
+                        if games.peek(|g| g.blobs.referenced_size()) >= MAX_BLOB_STORE_SIZE {
                             let mut res = StatusCode::INSUFFICIENT_STORAGE.into_response();
                             *res.body_mut() = Body::from(include_str!("./overloaded.html"));
                             return Ok(res);
                         }
-                        let bad_req = |e: anyhow::Error| {
-                            let mut res = StatusCode::INSUFFICIENT_STORAGE.into_response();
-                            *res.body_mut() = Body::from(format!(
-                                include_str!("./invalid_pack.html.template"),
-                                error = e,
-                                error_dbg = serde_json::to_string(&format!("{e:?}")).unwrap(),
-                                num = NUM_CHARS,
-                            ));
-                            res
+                        let mut settings = match NewGame::from_multipart(&mut multipart).await {
+                            Ok(settings) => settings,
+                            Err(e) => match e.downcast_ref::<InvalidSettings>() {
+                                Some(e) => return Ok(invalid_settings(e)),
+                                None => return Err(e),
+                            },
                         };
-                        while let Some(field) = multipart.next_field().await? {
-                            if field.name() == Some("character_pack") {
-                                let bytes = field.bytes().await?;
-                                set = Some(match games.mutate(|g| g.cache.load(bytes)) {
-                                    Ok(a) => a,
-                                    Err(e) => return Ok(bad_req(e.into())),
-                                });
-                            }
-                        }
-                        let Some(set) = set else {
-                            return Ok(bad_req(anyhow!("character pack required")));
+                        let (characters, report) = match settings.characters(&games).await {
+                            Ok(a) => a,
+                            Err(e) if is_server_error(&e) => return Err(e),
+                            Err(e) => match e.downcast_ref::<InvalidSettings>() {
+                                Some(e) => return Ok(invalid_settings(e)),
+                                None => return Ok(invalid_pack(e, settings.rows * settings.cols)),
+                            },
+                        };
+                        let uid = session.ok().map(|Session(uid)| uid);
+                        let (game_id, host_id) = start_game(&games, settings, characters, uid);
+                        let url = format!("/game/{game_id}/");
+                        let mut res = match report {
+                            Some(report) => pack_loaded(
+                                "Your character pack is ready",
+                                &report,
+                                &url,
+                                "Go to the game",
+                            ),
+                            None => Redirect::to(&url).into_response(),
                         };
-                        let uid = headers
-                            .get("cookie")
-                            .and_then(|c| c.to_str().ok())
-                            .and_then(|c| {
-                                c.split(";").find_map(|c| c.trim().strip_prefix("user_id="))
-                            })
-                            .and_then(|c| c.parse::<u64>().ok());
-                        let mut p0 = PlayerState::random();
-                        p0.id = uid.unwrap_or(p0.id);
-                        let p0_id = p0.id;
-                        games.mutate(|g| {
-                            g.games.retain(|_, g| {
-                                if let Some(game) = g.get() {
-                                    !game.peek(|g| Some(g.p0.id) == uid || Some(g.p1.id) == uid)
-                                // destroy the user's previous game
-                                } else {
-                                    false
-                                }
-                            });
-                            g.games.insert(
-                                game_id,
-                                TimedResource::new(
-                                    SyncMutex::new(GameState {
-                                        characters: set,
-                                        events: broadcast::channel(10).0,
-                                        p0,
-                                        p1: PlayerState::random(),
-                                    }),
-                                    Duration::from_secs(60 * 60 * 4),
-                                ),
-                            );
-                            eprintln!("{} games are active", g.games.len());
-                        });
-                        let mut res = Redirect::to(&format!("/game/{game_id}/")).into_response();
                         res.headers_mut().insert(
                             "set-cookie",
-                            HeaderValue::from_str(&format!("user_id={p0_id}; SameSite=Strict"))?,
+                            games.peek(|g| g.session_key.cookie(host_id, is_https(&headers))),
                         );
                         Ok(res)
                     }
                     .await
                     .map_err(|e: anyhow::Error| {
+                        if let Some(res) = upload_too_large(&e) {
+                            return res;
+                        }
                         eprintln!("{e}");
                         eprintln!("{e:?}");
                         let mut res = StatusCode::INTERNAL_SERVER_ERROR.into_response();
@@ -293,75 +1596,122 @@ async fn main() {
             )
             .route("/game/{game_id}/", {
                 let games = games.clone();
-                get(|Path::<u64>(game_id), headers: HeaderMap| async move {
+                get(|Path::<u64>(game_id),
+                     Query(InviteParams { invite }): Query<InviteParams>,
+                     headers: HeaderMap,
+                     player: Result<Player, SessionRejection>| async move {
                     async {
                         let Some(game) =
                             games.peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
                         else {
-                            let mut res = StatusCode::NOT_FOUND.into_response();
-                            *res.body_mut() = Body::from(include_str!("./not_found.html"));
-                            res.headers_mut()
-                                .insert("content-type", HeaderValue::from_static("text/html"));
-                            return Ok(res);
+                            return Ok(SessionRejection::NotFound.into_response());
                         };
 
-                        let uid = headers
-                            .get("cookie")
-                            .and_then(|c| c.to_str().ok())
-                            .and_then(|c| {
-                                c.split(";").find_map(|c| c.trim().strip_prefix("user_id="))
-                            })
-                            .and_then(|c| c.parse::<u64>().ok())
-                            .filter(|uid| game.mutate(|g| g.claim(*uid)));
-                        if uid.is_some() {
-                            let game_board = format!(
-                                "<table>{}</table>",
-                                (0..NUM_ROWS)
-                                    .map(|row| format!(
-                                        "<tr>{}</tr>",
-                                        (0..NUM_COLS)
-                                            .map(|col| format!(
-                                                include_str!("./game-cell.html.template"),
-                                                row = row,
-                                                col = col,
-                                            ))
+                        if let Ok(Player { id: uid, .. }) = player {
+                            let body = game.peek(|g| {
+                                let my_name = g
+                                    .player(uid)
+                                    .and_then(|p| g.characters.0[p.character].name.as_deref())
+                                    .map(escape_html)
+                                    .unwrap_or_default();
+                                let attributes = g.characters.attributes();
+                                let ask_form = if attributes.is_empty() {
+                                    String::new()
+                                } else {
+                                    format!(
+                                        include_str!("./ask-form.html.template"),
+                                        options = attributes
+                                            .into_iter()
+                                            .map(|(attribute, values)| {
+                                                let attribute = escape_html(attribute);
+                                                format!(
+                                                    "<optgroup label=\"{attribute}\">{}</optgroup>",
+                                                    values
+                                                        .into_iter()
+                                                        .map(|value| {
+                                                            let value = escape_html(value);
+                                                            format!(
+                                                                "<option data-attribute=\"{attribute}\" data-value=\"{value}\">{value}</option>"
+                                                            )
+                                                        })
+                                                        .collect::<String>()
+                                                )
+                                            })
                                             .collect::<String>()
-                                    ))
-                                    .collect::<String>()
-                            );
+                                    )
+                                };
+                                let turn_bar = g
+                                    .turn
+                                    .map(|turn| {
+                                        format!(
+                                            include_str!("./turn-bar.html.template"),
+                                            status = if turn == uid {
+                                                "Your turn".to_owned()
+                                            } else if g.players.len() == 2 {
+                                                "Their turn".to_owned()
+                                            } else {
+                                                format!("{}'s turn", g.label(turn))
+                                            },
+                                        )
+                                    })
+                                    .unwrap_or_default();
+                                let target = match g.opponent(uid) {
+                                    Some(opponent) if g.players.len() > 2 => format!(
+                                        "You are guessing {}'s character.",
+                                        g.label(opponent.id)
+                                    ),
+                                    _ => String::new(),
+                                };
+                                let results = g.results(Some(uid)).unwrap_or_default();
+                                let invites = if g.is_host(uid) {
+                                    g.invites(&format!("{}/game/{game_id}/", origin(&headers)))
+                                } else {
+                                    String::new()
+                                };
+                                format!(
+                                    include_str!("./game.html.template"),
+                                    stylesheet = include_str!("./stylesheet.css"),
+                                    common = include_str!("./common.js"),
+                                    javascript = include_str!("./javascript.js"),
+                                    user_id = uid,
+                                    my_seat = g.seat(uid).unwrap_or_default(),
+                                    num_players = g.players.len(),
+                                    game_board = g.board(Some(uid)),
+                                    event_log = g.log.html(g.seat(uid), g.players.len()),
+                                    seq = g.seq,
+                                    round = g.round,
+                                    my_name = my_name,
+                                    target = target,
+                                    ask_form = ask_form,
+                                    turn_bar = turn_bar,
+                                    game_over = if results.is_empty() { "" } else { "game-over" },
+                                    results = results,
+                                    spectator_key = g.spectator_key,
+                                    spectators = g.spectators,
+                                    call_hidden = if g.players.len() > 2 { "hidden" } else { "" },
+                                    invites = invites,
+                                )
+                            });
 
                             let mut res = StatusCode::OK.into_response();
-                            *res.body_mut() = Body::from(format!(
-                                include_str!("./game.html.template"),
-                                stylesheet = include_str!("./stylesheet.css"),
-                                javascript = include_str!("./javascript.js"),
-                                game_board = game_board
-                            ));
+                            *res.body_mut() = Body::from(body);
                             res.headers_mut()
                                 .insert("content-type", HeaderValue::from_static("text/html"));
 
                             Ok(res)
                         } else {
-                            let Some(uid) = game.mutate(|g| {
-                                if !g.p0.claimed {
-                                    Some(g.p0.id)
-                                } else if !g.p1.claimed {
-                                    Some(g.p1.id)
-                                } else {
-                                    None
-                                }
+                            let Some(invite) = invite.filter(|&invite| {
+                                game.peek(|g| {
+                                    g.players.iter().any(|p| !p.claimed && p.invite == invite)
+                                })
                             }) else {
-                                let mut res = StatusCode::UNAUTHORIZED.into_response();
-                                *res.body_mut() = Body::from(include_str!("./unauthorized.html"));
-                                res.headers_mut()
-                                    .insert("content-type", HeaderValue::from_static("text/html"));
-                                return Ok(res);
+                                return Ok(SessionRejection::Unauthorized.into_response());
                             };
 
                             let mut res = StatusCode::OK.into_response();
                             *res.body_mut() = Body::from(format!(
                                 include_str!("./claim.html.template"),
-                                user_id = uid
+                                invite = invite
                             ));
                             res.headers_mut()
                                 .insert("content-type", HeaderValue::from_static("text/html"));
@@ -381,10 +1731,107 @@ async fn main() {
                     })
                 })
             })
+            .route("/game/{game_id}/claim", {
+                let games = games.clone();
+                post(|Path::<u64>(game_id),
+                      Query(InviteParams { invite }): Query<InviteParams>,
+                      headers: HeaderMap,
+                      player: Result<Player, SessionRejection>| async move {
+                    let Some(game) = games.peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
+                    else {
+                        return SessionRejection::NotFound.into_response();
+                    };
+                    let mut res = Redirect::to(&format!("/game/{game_id}/")).into_response();
+                    if player.is_ok() {
+                        // already sitting in this game
+                        return res;
+                    }
+                    let Some((_, uid)) = invite.and_then(|invite| game.mutate(|g| g.claim_invite(invite))) else {
+                        return SessionRejection::Unauthorized.into_response();
+                    };
+                    res.headers_mut()
+                        .insert("set-cookie", games.peek(|g| g.session_key.cookie(uid, is_https(&headers))));
+                    res
+                })
+            })
+            .route(
+                "/game/{game_id}/invites/{seat}",
+                post(|Path::<(u64, usize)>((game_id, seat)),
+                      headers: HeaderMap,
+                      Player { game, id: uid, .. }: Player| async move {
+                    let invite = game.mutate(|g| {
+                        if !g.is_host(uid) {
+                            return Err(anyhow!("only the host can reissue invites"));
+                        }
+                        g.reissue_invite(seat)
+                    });
+                    match invite {
+                        Ok(invite) => Json(serde_json::json!({
+                            "link": format!("{}/game/{game_id}/?invite={invite}", origin(&headers)),
+                        }))
+                        .into_response(),
+                        Err(e) => (
+                            StatusCode::CONFLICT,
+                            Json(serde_json::json!({ "error": e.to_string() })),
+                        )
+                            .into_response(),
+                    }
+                }),
+            )
+            .route(
+                "/game/{game_id}/invites/{seat}/qr.svg",
+                get(|Path::<(u64, usize)>((game_id, seat)),
+                     headers: HeaderMap,
+                     Player { game, id: uid, .. }: Player| async move {
+                    let Some(invite) = game.peek(|g| {
+                        g.players
+                            .get(seat)
+                            .filter(|p| g.is_host(uid) && !p.claimed)
+                            .map(|p| p.invite)
+                    }) else {
+                        return StatusCode::NOT_FOUND.into_response();
+                    };
+                    let link = format!("{}/game/{game_id}/?invite={invite}", origin(&headers));
+                    match qrcode::QrCode::new(link) {
+                        Ok(code) => {
+                            let svg = code
+                                .render::<qrcode::render::svg::Color>()
+                                .min_dimensions(160, 160)
+                                .build();
+                            let mut res = StatusCode::OK.into_response();
+                            *res.body_mut() = Body::from(svg);
+                            res.headers_mut()
+                                .insert("content-type", HeaderValue::from_static("image/svg+xml"));
+                            res.headers_mut()
+                                .insert("cache-control", HeaderValue::from_static("no-store"));
+                            res
+                        }
+                        Err(e) => {
+                            eprintln!("{e}");
+                            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                        }
+                    }
+                }),
+            )
+            .route("/game/{game_id}/ice-config", {
+                let games = games.clone();
+                get(|headers: HeaderMap, Player { id: uid, .. }: Player| async move {
+                    let host = headers
+                        .get("x-forwarded-host")
+                        .or_else(|| headers.get("host"))
+                        .and_then(|h| h.to_str().ok())
+                        .unwrap_or("localhost");
+                    Json(serde_json::json!({
+                        "iceServers": games.peek(|g| g.ice.servers(host, uid, GAME_LIFETIME)),
+                    }))
+                })
+            })
             .route("/game/{game_id}/img-{image_id}", {
                 let games = games.clone();
                 get(
-                    |Path::<(u64, String)>((game_id, image_id)), headers: HeaderMap| async move {
+                    |Path::<(u64, String)>((game_id, image_id)),
+                     Query(ImageParams { size }): Query<ImageParams>,
+                     player: Result<Player, SessionRejection>| async move {
                         async {
                             let Some(game) =
                                 games.peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
@@ -395,43 +1842,20 @@ async fn main() {
                             };
 
                             let char_idx = if &*image_id == "mine" {
-                                let Some(player_data) = headers
-                                    .get("cookie")
-                                    .and_then(|c| c.to_str().ok())
-                                    .and_then(|c| {
-                                        c.split(";").find_map(|c| c.trim().strip_prefix("user_id="))
-                                    })
-                                    .and_then(|c| c.parse::<u64>().ok())
-                                    .and_then(|uid| {
-                                        game.peek(|g| {
-                                            if g.p0.id == uid {
-                                                Some(g.p0)
-                                            } else if g.p1.id == uid {
-                                                Some(g.p1)
-                                            } else {
-                                                None
-                                            }
-                                        })
-                                    })
+                                let Some(character) = player
+                                    .ok()
+                                    .and_then(|p| game.peek(|g| g.player(p.id).map(|p| p.character)))
                                 else {
-                                    let mut res = StatusCode::UNAUTHORIZED.into_response();
-                                    *res.body_mut() =
-                                        Body::from(include_str!("./unauthorized.html"));
-                                    res.headers_mut().insert(
-                                        "content-type",
-                                        HeaderValue::from_static("text/html"),
-                                    );
-                                    return Ok(res);
+                                    return Ok(SessionRejection::Unauthorized.into_response());
                                 };
 
-                                player_data.character
+                                character
                             } else {
-                                let Some((row, col)) =
+                                let Some(char_idx) =
                                     image_id.split_once("_").and_then(|(row, col)| {
-                                        Some((
-                                            row.parse::<usize>().ok()?,
-                                            col.parse::<usize>().ok()?,
-                                        ))
+                                        let row = row.parse::<usize>().ok()?;
+                                        let col = col.parse::<usize>().ok()?;
+                                        game.peek(|g| g.char_idx(row, col))
                                     })
                                 else {
                                     let mut res = StatusCode::NOT_FOUND.into_response();
@@ -443,12 +1867,12 @@ async fn main() {
                                     return Ok(res);
                                 };
 
-                                row * NUM_COLS + col
+                                char_idx
                             };
 
-                            Ok(game
-                                .peek(|g| g.characters.0[char_idx].clone())
-                                .to_response())
+                            game.peek(|g| g.characters.0[char_idx].clone())
+                                .to_response(size)
+                                .await
                         }
                         .await
                         .map_err(|e: anyhow::Error| {
@@ -463,63 +1887,253 @@ async fn main() {
                     },
                 )
             })
-            .route("/game/{game_id}/guess", {
+            .route("/game/{game_id}/watch", {
                 let games = games.clone();
-                async fn guess(
-                    games: Arc<SyncMutex<AppState>>,
-                    game_id: u64,
-                    GuessParams { row, col }: GuessParams,
-                    headers: HeaderMap,
-                ) -> Result<Response, anyhow::Error> {
+                get(
+                    |Path::<u64>(game_id), Query(SpectateParams { key, seat }): Query<SpectateParams>| async move {
+                        let Some(game) = games
+                            .peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
+                            .filter(|game| game.peek(|g| g.spectator_key == key))
+                        else {
+                            let mut res = StatusCode::NOT_FOUND.into_response();
+                            *res.body_mut() = Body::from(include_str!("./not_found.html"));
+                            res.headers_mut()
+                                .insert("content-type", HeaderValue::from_static("text/html"));
+                            return res;
+                        };
+                        let seat = seat.filter(|&seat| seat < game.peek(|g| g.players.len()));
+                        let (game_board, status, results, spectators, seq, round, follow_options, event_log) = game.peek(|g| {
+                            (
+                                g.board(seat.map(|seat| g.players[seat].id)),
+                                g.turn
+                                    .map(|turn| format!("{}'s turn", g.label(turn)))
+                                    .unwrap_or_default(),
+                                g.results(None).unwrap_or_default(),
+                                g.spectators,
+                                g.seq,
+                                g.round,
+                                (0..g.players.len())
+                                    .map(|s| {
+                                        format!(
+                                            "<option value=\"{s}\"{}>Player {}</option>",
+                                            if Some(s) == seat { " selected" } else { "" },
+                                            s + 1
+                                        )
+                                    })
+                                    .collect::<String>(),
+                                g.log.html(None, g.players.len()),
+                            )
+                        });
+                        let mut res = StatusCode::OK.into_response();
+                        *res.body_mut() = Body::from(format!(
+                            include_str!("./spectate.html.template"),
+                            stylesheet = include_str!("./stylesheet.css"),
+                            common = include_str!("./common.js"),
+                            javascript = include_str!("./spectate.js"),
+                            spectator_key = key,
+                            spectators = spectators,
+                            status = status,
+                            results = results,
+                            game_board = game_board,
+                            seq = seq,
+                            round = round,
+                            following = seat.map_or("null".to_owned(), |seat| seat.to_string()),
+                            follow_options = follow_options,
+                            event_log = event_log,
+                        ));
+                        res.headers_mut()
+                            .insert("content-type", HeaderValue::from_static("text/html"));
+                        res
+                    },
+                )
+            })
+            .route("/game/{game_id}/watch-ws", {
+                let games = games.clone();
+                any(
+                    |Path::<u64>(game_id),
+                     Query(SpectateParams { key, .. }): Query<SpectateParams>,
+                     Query(ResumeParams { since }): Query<ResumeParams>,
+                     ws: WebSocketUpgrade| async move {
+                        let Some(game) = games
+                            .peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
+                            .filter(|game| game.peek(|g| g.spectator_key == key))
+                        else {
+                            let mut res = StatusCode::NOT_FOUND.into_response();
+                            *res.body_mut() = Body::from(include_str!("./not_found.html"));
+                            return res;
+                        };
+                        ws.on_upgrade(move |mut ws| async move {
+                            let (mut sub, mut seq, missed) = game.mutate(|g| {
+                                let res = (g.events.subscribe(), g.seq, g.catch_up(since, None));
+                                g.set_spectating(true);
+                                res
+                            });
+                            let mut open = true;
+                            if let Err(e) = async {
+                                for msg in missed? {
+                                    ws.send(msg).await?;
+                                }
+                                loop {
+                                    tokio::select! {
+                                        event = sub.recv() => match event {
+                                            Ok((n, e)) if n > seq => {
+                                                seq = n;
+                                                if e.public() {
+                                                    ws.send(game.peek(|g| g.ws_message(Some(n), &e, None))?).await?;
+                                                }
+                                            }
+                                            Ok(_) => (),
+                                            Err(broadcast::error::RecvError::Lagged(_)) => {
+                                                let (n, msg) = game.peek(|g| (g.seq, g.catch_up(Some(seq), None)));
+                                                seq = n;
+                                                for msg in msg? {
+                                                    ws.send(msg).await?;
+                                                }
+                                            }
+                                            Err(broadcast::error::RecvError::Closed) => {
+                                                break;
+                                            }
+                                        },
+                                        msg = ws.recv() => {
+                                            // spectators can't send anything but control messages
+                                            match msg.transpose()? {
+                                                Some(Message::Close(a)) => {
+                                                    ws.send(Message::Close(a)).await?;
+                                                    open = false;
+                                                    break;
+                                                }
+                                                Some(Message::Ping(a)) => {
+                                                    ws.send(Message::Pong(a)).await?;
+                                                }
+                                                Some(msg) => {
+                                                    eprintln!("unexpected ws message from spectator {msg:?}");
+                                                }
+                                                None => {
+                                                    open = false;
+                                                    break;
+                                                }
+                                            }
+                                        },
+                                    }
+                                }
+                                if open {
+                                    ws.send(Message::Close(Some(CloseFrame {
+                                        code: 1000,
+                                        reason: "complete".into(),
+                                    })))
+                                    .await?;
+                                    ws.recv().await;
+                                }
+                                drop(ws);
+
+                                Ok::<_, anyhow::Error>(())
+                            }
+                            .await
+                            {
+                                eprintln!("{e}");
+                                eprintln!("{e:?}");
+                            }
+                            game.mutate(|g| g.set_spectating(false));
+                        })
+                    },
+                )
+            })
+            .route("/game/{game_id}/results", {
+                let games = games.clone();
+                get(|Path::<u64>(game_id), Query(ResultsParams { key }): Query<ResultsParams>, player: Result<Player, SessionRejection>| async move {
                     let Some(game) = games.peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
                     else {
                         let mut res = StatusCode::NOT_FOUND.into_response();
                         *res.body_mut() = Body::from(include_str!("./not_found.html"));
-                        return Ok(res);
+                        return res;
                     };
-
-                    let Some((uid, other_player_data)) = headers
-                        .get("cookie")
-                        .and_then(|c| c.to_str().ok())
-                        .and_then(|c| c.split(";").find_map(|c| c.trim().strip_prefix("user_id=")))
-                        .and_then(|c| c.parse::<u64>().ok())
-                        .and_then(|uid| {
-                            game.peek(|g| {
-                                if g.p0.id == uid {
-                                    Some((uid, g.p1))
-                                } else if g.p1.id == uid {
-                                    Some((uid, g.p0))
-                                } else {
-                                    None
-                                }
-                            })
-                        })
+                    // players see their own point of view, and spectators see
+                    // the game from no one's
+                    let Some(viewer) = (if key.is_some() {
+                        key.filter(|key| game.peek(|g| g.spectator_key == *key))
+                            .map(|_| None)
+                    } else {
+                        player.ok().map(|p| Some(p.id))
+                    }) else {
+                        return SessionRejection::Unauthorized.into_response();
+                    };
+                    let Some(results) = game.peek(|g| g.results(viewer)) else {
+                        return StatusCode::CONFLICT.into_response();
+                    };
+                    let mut res = StatusCode::OK.into_response();
+                    *res.body_mut() = Body::from(results);
+                    res.headers_mut()
+                        .insert("content-type", HeaderValue::from_static("text/html"));
+                    res
+                })
+            })
+            .route("/game/{game_id}/log.{format}", {
+                let games = games.clone();
+                get(|Path::<(u64, String)>((game_id, format)), Query(ResultsParams { key }): Query<ResultsParams>, player: Result<Player, SessionRejection>| async move {
+                    let Some(game) = games.peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
                     else {
-                        let mut res = StatusCode::UNAUTHORIZED.into_response();
-                        *res.body_mut() = Body::from(include_str!("./unauthorized.html"));
-                        res.headers_mut()
-                            .insert("content-type", HeaderValue::from_static("text/html"));
-                        return Ok(res);
+                        let mut res = StatusCode::NOT_FOUND.into_response();
+                        *res.body_mut() = Body::from(include_str!("./not_found.html"));
+                        return res;
                     };
-
-                    let correct = row * NUM_COLS + col == other_player_data.character;
-
-                    game.mutate(|g| {
-                        let player_data = if g.p0.id == uid { &mut g.p0 } else { &mut g.p1 };
-                        if correct {
-                            player_data.correct = true;
-                        } else {
-                            player_data.incorrect_count += 1;
+                    let allowed = if key.is_some() {
+                        key.is_some_and(|key| game.peek(|g| g.spectator_key == key))
+                    } else {
+                        player.is_ok()
+                    };
+                    if !allowed {
+                        return SessionRejection::Unauthorized.into_response();
+                    }
+                    // the log is only handed out once the game is over
+                    let export = game.peek(|g| {
+                        if g.outcome.is_none() {
+                            return Err(StatusCode::CONFLICT);
+                        }
+                        match &*format {
+                            "txt" => Ok((g.log.text(g.players.len()), "text/plain; charset=utf-8")),
+                            "json" => serde_json::to_string(g.log.entries())
+                                .map(|json| (json, "application/json"))
+                                .map_err(|e| {
+                                    eprintln!("{e}");
+                                    StatusCode::INTERNAL_SERVER_ERROR
+                                }),
+                            _ => Err(StatusCode::NOT_FOUND),
                         }
-                        let _ = g.events.send(if correct {
-                            GameEvent::Correct {
-                                user_id: uid,
-                                tries: player_data.incorrect_count + 1,
-                            }
-                        } else {
-                            GameEvent::Incorrect { user_id: uid }
-                        });
                     });
+                    let (body, content_type) = match export {
+                        Ok(export) => export,
+                        Err(status) => return status.into_response(),
+                    };
+                    let mut res = StatusCode::OK.into_response();
+                    *res.body_mut() = Body::from(body);
+                    res.headers_mut()
+                        .insert("content-type", HeaderValue::from_static(content_type));
+                    res
+                })
+            })
+            .route("/game/{game_id}/guess", {
+                async fn guess(
+                    Player { game, id: uid, .. }: Player,
+                    GuessParams { row, col }: GuessParams,
+                ) -> Result<Response, anyhow::Error> {
+                    let Some(char_idx) = game.peek(|g| g.char_idx(row, col)) else {
+                        return Ok(StatusCode::BAD_REQUEST.into_response());
+                    };
+                    let correct = match game.mutate(|g| g.guess(uid, char_idx)) {
+                        Ok(correct) => correct,
+                        Err(e) => {
+                            let mut res = StatusCode::CONFLICT.into_response();
+                            *res.body_mut() =
+                                Body::from(serde_json::to_string(&serde_json::json!({
+                                    "error": e.to_string(),
+                                }))?);
+                            res.headers_mut().insert(
+                                "content-type",
+                                HeaderValue::from_static("application/json"),
+                            );
+                            return Ok(res);
+                        }
+                    };
 
                     let mut res = StatusCode::OK.into_response();
                     *res.body_mut() = Body::from(format!("{{ \"correct\": {correct} }} "));
@@ -528,8 +2142,8 @@ async fn main() {
                     Ok(res)
                 }
                 post(
-                    |Path(game_id), Query(guess_params), headers: HeaderMap| async move {
-                        guess(games, game_id, guess_params, headers).await.map_err(
+                    |player: Player, Query(guess_params)| async move {
+                        guess(player, guess_params).await.map_err(
                             |e: anyhow::Error| {
                                 eprintln!("{e}");
                                 eprintln!("{e:?}");
@@ -544,61 +2158,66 @@ async fn main() {
                 )
             })
             .route("/game/{game_id}/ws", {
-                let games = games.clone();
                 any(
-                    |Path::<u64>(game_id), ws: WebSocketUpgrade, headers: HeaderMap| async move {
-                        let Some(game) =
-                            games.peek(|g| g.games.get(&game_id).and_then(|g| g.get()))
-                        else {
-                            let mut res = StatusCode::NOT_FOUND.into_response();
-                            *res.body_mut() = Body::from(include_str!("./not_found.html"));
-                            return res;
-                        };
-                        let Some(uid) = headers
-                            .get("cookie")
-                            .and_then(|c| c.to_str().ok())
-                            .and_then(|c| {
-                                c.split(";").find_map(|c| c.trim().strip_prefix("user_id="))
-                            })
-                            .and_then(|c| c.parse::<u64>().ok())
-                            .filter(|uid| game.mutate(|g| g.claim(*uid)))
-                        else {
-                            let mut res = StatusCode::UNAUTHORIZED.into_response();
-                            *res.body_mut() = Body::from(include_str!("./unauthorized.html"));
-                            res.headers_mut()
-                                .insert("content-type", HeaderValue::from_static("text/html"));
-                            return res;
-                        };
-                        let mut sub = game.peek(|g| g.events.subscribe());
+                    |Player { game, id: uid, .. }: Player,
+                     Query(ResumeParams { since }): Query<ResumeParams>,
+                     ws: WebSocketUpgrade| async move {
                         ws.on_upgrade(move |mut ws| async move {
+                            let (mut sub, mut seq, missed, others) = game.mutate(|g| {
+                                (
+                                    g.events.subscribe(),
+                                    g.seq,
+                                    g.catch_up(since, Some(uid)),
+                                    g.set_connected(uid, true),
+                                )
+                            });
                             let mut open = true;
                             if let Err(e) = async {
-                                if let Some(other) = game.mutate(|g| g.set_connected(uid, true)) {
-                                    ws.send(Message::Text(
-                                        serde_json::to_string(&GameEvent::Connected { user_id: other })?.into(),
-                                    ))
+                                for msg in missed? {
+                                    ws.send(msg).await?;
+                                }
+                                for other in others {
+                                    ws.send(game.peek(|g| {
+                                        g.ws_message(None, &GameEvent::Connected { user_id: other }, Some(uid))
+                                    })?)
                                     .await?;
                                 }
                                 loop {
                                     tokio::select! {
                                         event = sub.recv() => match event {
-                                            Ok(e) if e.user_id() != uid => {
-                                                ws.send(Message::Text(
-                                                    serde_json::to_string(&e)?.into(),
-                                                ))
-                                                .await?;
+                                            Ok((n, e)) if n > seq => {
+                                                seq = n;
+                                                if e.visible_to(Some(uid)) {
+                                                    ws.send(game.peek(|g| g.ws_message(Some(n), &e, Some(uid)))?).await?;
+                                                }
+                                            }
+                                            Ok(_) => (),
+                                            Err(broadcast::error::RecvError::Lagged(_)) => {
+                                                let (n, msg) = game.peek(|g| (g.seq, g.catch_up(Some(seq), Some(uid))));
+                                                seq = n;
+                                                for msg in msg? {
+                                                    ws.send(msg).await?;
+                                                }
                                             }
                                             Err(broadcast::error::RecvError::Closed) => {
                                                 break;
                                             }
-                                            _ => (),
                                         },
                                         msg = ws.recv() => {
                                             if let Some(msg) = msg.transpose()? {
                                                 match msg {
                                                     Message::Text(json) => {
-                                                        let event = serde_json::from_str::<GameEvent>(&json)?.handle_user_event(uid)?;
-                                                        let _ = game.mutate(|g| g.events.send(event));
+                                                        let event = serde_json::from_str::<GameEvent>(&json)?;
+                                                        if let Err(e) = game.mutate(|g| event.handle_user_event(uid, g)) {
+                                                            ws.send(Message::Text(
+                                                                serde_json::to_string(&GameEvent::Error {
+                                                                    user_id: uid,
+                                                                    message: e.to_string(),
+                                                                })?
+                                                                .into(),
+                                                            ))
+                                                            .await?;
+                                                        }
                                                     }
                                                     Message::Close(a) => {
                                                         ws.send(Message::Close(a)).await?;
@@ -641,7 +2260,11 @@ async fn main() {
                     },
                 )
             })
-            .layer(DefaultBodyLimit::max(1024 * 1024 * 128));
+            .nest("/api/v1", api::router(games.clone()))
+            .layer(Extension(games.clone()))
+            .layer(DefaultBodyLimit::max(
+                games.peek(|g| g.pack_limits.max_upload_size) as usize,
+            ));
     // on load - select who they are guessing
     // show other player's link
 
@@ -649,5 +2272,13 @@ async fn main() {
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    tokio::select! {
+        res = axum::serve(listener, app) => res.unwrap(),
+        _ = shutdown_signal() => (),
+    }
+
+    let games = games.clone();
+    tokio::task::spawn_blocking(move || persist(&games))
+        .await
+        .unwrap();
 }
@@ -1,123 +1,1183 @@
-use std::collections::HashSet;
-use std::hash::Hash;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::io::{Cursor, Read};
-use std::mem::MaybeUninit;
-use std::sync::{Arc, Weak};
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use axum::body::{Body, Bytes};
 use axum::http::{HeaderValue, Response, StatusCode};
 use axum::response::IntoResponse;
-use bytes::BytesMut;
+use flate2::read::GzDecoder;
 use rand::rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use zip::ZipArchive;
 
-use crate::NUM_CHARS;
+use crate::blobs::{Blob, BlobStore};
+use crate::images::{self, ImageConfig, Rejected};
+use crate::utils::{env_var, escape_html};
 
-struct WeakHashable<T>(Weak<T>);
-impl<T: Hash + Default> Hash for WeakHashable<T> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        if let Some(b) = self.0.upgrade() {
-            b.hash(state);
+/// Optional `manifest.json` or `manifest.toml` at the root of a pack, or in
+/// the folder everything in the pack is in, naming each image and describing
+/// its traits.
+#[derive(Debug, Default, Deserialize)]
+struct ManifestFile {
+    #[serde(default)]
+    characters: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ManifestEntry {
+    file: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    attributes: BTreeMap<String, String>,
+}
+
+#[derive(Default)]
+struct Manifest {
+    /// the folder the manifest was found in, which its paths are relative to
+    dir: String,
+    entries: HashMap<String, ManifestEntry>,
+}
+impl Manifest {
+    fn find(files: &[PackFile]) -> Result<Self, anyhow::Error> {
+        let dir = top_dir(files);
+        let read = |name: &str| {
+            let path = format!("{dir}{name}");
+            let Some(file) = files.iter().find(|f| f.name == path) else {
+                return Ok(None);
+            };
+            let data = file
+                .data
+                .as_ref()
+                .map_err(|e| anyhow!("invalid {name}: {e}"))?;
+            String::from_utf8(data.to_vec())
+                .map(Some)
+                .map_err(|e| anyhow!("invalid {name}: {e}"))
+        };
+        let manifest = if let Some(json) = read("manifest.json")? {
+            serde_json::from_str::<ManifestFile>(&json)
+                .map_err(|e| anyhow!("invalid manifest.json: {e}"))?
+        } else if let Some(toml) = read("manifest.toml")? {
+            toml::from_str::<ManifestFile>(&toml)
+                .map_err(|e| anyhow!("invalid manifest.toml: {e}"))?
         } else {
-            T::default().hash(state);
+            ManifestFile::default()
+        };
+        Ok(Self {
+            dir: dir.to_owned(),
+            entries: manifest
+                .characters
+                .into_iter()
+                .map(|c| (c.file.clone(), c))
+                .collect(),
+        })
+    }
+
+    /// Looks up an entry by its path from the manifest's folder, falling back
+    /// to the bare file name so packs zipped from inside a folder still match.
+    fn take(&mut self, path: &str) -> ManifestEntry {
+        let path = path.strip_prefix(&self.dir).unwrap_or(path);
+        self.entries
+            .remove(path)
+            .or_else(|| self.entries.remove(path.rsplit('/').next()?))
+            .unwrap_or_default()
+    }
+
+    /// Whether `path` is where a manifest would be read from, rather than one
+    /// further down that is ignored.
+    fn is_top(&self, path: &str) -> bool {
+        path.strip_prefix(&self.dir)
+            .is_some_and(|name| !name.contains('/'))
+    }
+}
+
+/// The folder every file in the pack is in, with a trailing `/`, as zipping a
+/// folder rather than its contents leaves them. Empty if there isn't one.
+fn top_dir(files: &[PackFile]) -> &str {
+    let mut names = files
+        .iter()
+        .map(|f| f.name.as_str())
+        .filter(|name| !is_ignored(name) || is_manifest(name));
+    let Some(first) = names.next() else {
+        return "";
+    };
+    let Some(dir) = first.find('/').map(|end| &first[..=end]) else {
+        return "";
+    };
+    if names.all(|name| name.starts_with(dir)) {
+        dir
+    } else {
+        ""
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
+
+/// Files smaller than this aren't held to [`PackLimits`]'s compression
+/// ratio, since a tiny file of one colour can compress very well without
+/// being a threat.
+const RATIO_EXEMPT_SIZE: u64 = MIB;
+
+/// How much an uploaded pack may make the server unpack, so a zip bomb or
+/// tarball bomb can't fill up its memory.
+///
+/// Configured with:
+/// - `IMPOSTER_ROSTER_MAX_UPLOAD_SIZE`: the largest upload, in MiB
+/// - `IMPOSTER_ROSTER_MAX_PACK_FILES`: the most files a pack can have
+/// - `IMPOSTER_ROSTER_MAX_PACK_FILE_SIZE`: the largest a file can be once
+///   unpacked, in MiB
+/// - `IMPOSTER_ROSTER_MAX_PACK_SIZE`: the most that will be unpacked from a
+///   pack in total, in MiB
+/// - `IMPOSTER_ROSTER_MAX_COMPRESSION_RATIO`: how many times larger than
+///   its compressed size a file, or a whole tar.gz, can be
+/// - `IMPOSTER_ROSTER_MAX_PACK_DEPTH`: how many folders deep a file can be
+#[derive(Clone, Debug)]
+pub struct PackLimits {
+    pub max_upload_size: u64,
+    max_entries: usize,
+    max_entry_size: u64,
+    max_total_size: u64,
+    max_ratio: u64,
+    max_depth: usize,
+}
+impl Default for PackLimits {
+    fn default() -> Self {
+        Self {
+            max_upload_size: 128 * MIB,
+            max_entries: 1000,
+            max_entry_size: 32 * MIB,
+            max_total_size: 512 * MIB,
+            max_ratio: 100,
+            max_depth: 8,
         }
     }
 }
-impl<T: PartialEq> PartialEq for WeakHashable<T> {
-    fn eq(&self, other: &Self) -> bool {
-        self.0.upgrade() == other.0.upgrade()
+impl PackLimits {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let mut limits = Self::default();
+        if let Some(size) = env_var::<u64>("IMPOSTER_ROSTER_MAX_UPLOAD_SIZE")? {
+            limits.max_upload_size = size * MIB;
+        }
+        if let Some(count) = env_var("IMPOSTER_ROSTER_MAX_PACK_FILES")? {
+            limits.max_entries = count;
+        }
+        if let Some(size) = env_var::<u64>("IMPOSTER_ROSTER_MAX_PACK_FILE_SIZE")? {
+            limits.max_entry_size = size * MIB;
+        }
+        if let Some(size) = env_var::<u64>("IMPOSTER_ROSTER_MAX_PACK_SIZE")? {
+            limits.max_total_size = size * MIB;
+        }
+        if let Some(ratio) = env_var("IMPOSTER_ROSTER_MAX_COMPRESSION_RATIO")? {
+            limits.max_ratio = ratio;
+        }
+        if let Some(depth) = env_var("IMPOSTER_ROSTER_MAX_PACK_DEPTH")? {
+            limits.max_depth = depth;
+        }
+        Ok(limits)
+    }
+
+    /// Unpacks a file that claims to be `size` bytes, stopping as soon as it
+    /// turns out to be bigger than the limits allow rather than trusting the
+    /// archive. Files compressed on their own, as in a zip, also have their
+    /// `compressed_size` checked against the compression ratio.
+    fn read(
+        &self,
+        file: &mut impl Read,
+        size: u64,
+        compressed_size: Option<u64>,
+    ) -> Result<Vec<u8>, Rejected> {
+        let too_large = || {
+            Rejected::TooLarge(format!(
+                "files can be at most {} MiB",
+                self.max_entry_size / MIB
+            ))
+        };
+        let bomb = || {
+            Rejected::TooLarge(format!(
+                "unzips to more than {} times its compressed size",
+                self.max_ratio
+            ))
+        };
+        let max_expanded = compressed_size.map_or(u64::MAX, |size| self.max_expanded(size));
+        if size > self.max_entry_size {
+            return Err(too_large());
+        }
+        if size > max_expanded {
+            return Err(bomb());
+        }
+        let limit = self.max_entry_size.min(max_expanded);
+        let mut data = Vec::with_capacity(size as usize);
+        file.take(limit + 1)
+            .read_to_end(&mut data)
+            .map_err(|e| Rejected::Corrupt(format!("couldn't unpack: {e}")))?;
+        if data.len() as u64 > self.max_entry_size {
+            return Err(too_large());
+        }
+        if data.len() as u64 > limit {
+            return Err(bomb());
+        }
+        Ok(data)
+    }
+
+    /// The most that `compressed_size` bytes are allowed to expand to.
+    fn max_expanded(&self, compressed_size: u64) -> u64 {
+        compressed_size
+            .saturating_mul(self.max_ratio)
+            .max(RATIO_EXEMPT_SIZE)
+    }
+
+    fn too_many_files(&self) -> anyhow::Error {
+        anyhow!("packs can have at most {} files", self.max_entries)
     }
 }
-impl<T: Eq> Eq for WeakHashable<T> {}
 
-#[derive(Default)]
-pub struct CharacterCache(HashSet<WeakHashable<Character>>);
-impl CharacterCache {
-    pub fn size(&mut self) -> usize {
-        let mut res = 0;
-        self.0.retain(|w| {
-            if let Some(b) = w.0.upgrade() {
-                res += b.size();
-                true
-            } else {
-                false
+/// An uploaded pack, in one of the shapes we accept.
+pub enum PackSource {
+    Zip(Bytes),
+    Tar(Bytes),
+    TarGz(Bytes),
+    /// images uploaded on their own, with the names they were uploaded as
+    Images(Vec<(String, Bytes)>),
+}
+impl PackSource {
+    /// Makes a pack out of the files uploaded with it: either a single zip,
+    /// tar or tar.gz file, or any number of images, such as a folder of them
+    /// uploaded from the browser. Archives are recognised by their content
+    /// rather than their names.
+    pub fn new(uploads: Vec<(String, Bytes)>) -> Result<Option<Self>, anyhow::Error> {
+        let mut archives = Vec::new();
+        let mut images = Vec::new();
+        for (name, data) in uploads {
+            if let Some(kind) = unsupported_archive(&data) {
+                return Err(anyhow!(
+                    "{name} is a {kind} file, which isn't supported: upload a zip, tar or tar.gz file instead"
+                ));
+            }
+            match Self::archive(data) {
+                Ok(archive) => archives.push(archive),
+                Err(data) => images.push((name, data)),
+            }
+        }
+        match (archives.len(), images.is_empty()) {
+            (0, true) => Ok(None),
+            (0, false) => Ok(Some(Self::Images(images))),
+            (1, true) => Ok(archives.pop()),
+            _ => Err(anyhow!(
+                "upload either a single zip, tar or tar.gz file, or images on their own"
+            )),
+        }
+    }
+
+    /// Treats `data` as an archive if it looks like one, or gives it back.
+    fn archive(data: Bytes) -> Result<Self, Bytes> {
+        if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            Ok(Self::Zip(data))
+        } else if data.starts_with(b"\x1f\x8b") {
+            Ok(Self::TarGz(data))
+        } else if data.get(257..262) == Some(b"ustar") {
+            Ok(Self::Tar(data))
+        } else {
+            Err(data)
+        }
+    }
+
+    /// Unpacks every file in the pack, failing if the pack as a whole breaks
+    /// `limits`. Files that break them on their own are kept, with the reason
+    /// they couldn't be read, so they show up in the [`PackReport`].
+    fn unpack(self, limits: &PackLimits) -> Result<Vec<PackFile>, anyhow::Error> {
+        let mut files = Unpacked {
+            limits,
+            files: Vec::new(),
+            total: 0,
+        };
+        match self {
+            Self::Zip(data) => {
+                let mut zip = ZipArchive::new(Cursor::new(data))?;
+                // the central directory says how many entries there are, so
+                // there's no need to unzip any of them to know there are too
+                // many
+                if zip.len() > limits.max_entries {
+                    return Err(limits.too_many_files());
+                }
+                for idx in 0..zip.len() {
+                    let mut file = zip.by_index(idx)?;
+                    if file.is_dir() {
+                        continue;
+                    }
+                    let (size, compressed_size) = (file.size(), file.compressed_size());
+                    let data = limits.read(&mut file, size, Some(compressed_size));
+                    files.push(file.name().to_owned(), data)?;
+                }
             }
+            Self::Tar(data) => files.tar(Cursor::new(data), u64::MAX)?,
+            Self::TarGz(data) => {
+                let limit = limits.max_expanded(data.len() as u64);
+                files.tar(GzDecoder::new(Cursor::new(data)), limit)?;
+            }
+            Self::Images(images) => {
+                for (name, data) in images {
+                    let size = data.len() as u64;
+                    let data = limits.read(&mut Cursor::new(data), size, None);
+                    files.push(name, data)?;
+                }
+            }
+        }
+        Ok(files.files)
+    }
+}
+
+/// The kind of archive `data` is, if it is one packs can't be uploaded as.
+/// They're told apart so they aren't mistaken for images that didn't decode.
+fn unsupported_archive(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"7z\xbc\xaf\x27\x1c") {
+        Some("7z")
+    } else if data.starts_with(b"Rar!\x1a\x07") {
+        Some("rar")
+    } else if data.starts_with(b"\xfd7zXZ\x00") {
+        Some("xz")
+    } else if data.starts_with(b"BZh") {
+        Some("bzip2")
+    } else {
+        None
+    }
+}
+
+/// A file from a pack, unpacked but not looked at yet.
+struct PackFile {
+    name: String,
+    data: Result<Vec<u8>, Rejected>,
+}
+
+/// The files unpacked from a pack so far, making sure there aren't too many
+/// of them.
+struct Unpacked<'a> {
+    limits: &'a PackLimits,
+    files: Vec<PackFile>,
+    total: u64,
+}
+impl Unpacked<'_> {
+    fn push(&mut self, name: String, data: Result<Vec<u8>, Rejected>) -> Result<(), anyhow::Error> {
+        if self.files.len() == self.limits.max_entries {
+            return Err(self.limits.too_many_files());
+        }
+        if let Ok(data) = &data {
+            self.total += data.len() as u64;
+            if self.total > self.limits.max_total_size {
+                return Err(anyhow!(
+                    "the pack unpacks to more than {} MiB",
+                    self.limits.max_total_size / MIB
+                ));
+            }
+        }
+        // as tar leaves them when a pack is made with `tar -C dir .`
+        let name = match name.strip_prefix("./") {
+            Some(name) => name.to_owned(),
+            None => name,
+        };
+        self.files.push(PackFile { name, data });
+        Ok(())
+    }
+
+    /// Unpacks a tarball, giving up if it unpacks to more than `limit` bytes
+    /// altogether.
+    fn tar(&mut self, reader: impl Read, limit: u64) -> Result<(), anyhow::Error> {
+        let mut archive = tar::Archive::new(Expanded {
+            inner: reader,
+            remaining: limit,
         });
+        let invalid = |e: std::io::Error| anyhow!("couldn't read the tar file: {e}");
+        let res = (|| {
+            for entry in archive.entries().map_err(invalid)? {
+                let mut entry = entry.map_err(invalid)?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = entry
+                    .path()
+                    .map_err(invalid)?
+                    .to_string_lossy()
+                    .into_owned();
+                let size = entry.size();
+                let data = self.limits.read(&mut entry, size, None);
+                self.push(name, data)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })();
+        if archive.into_inner().remaining == 0 {
+            return Err(anyhow!(
+                "the tar.gz file unpacks to more than {} times its size",
+                self.limits.max_ratio
+            ));
+        }
         res
     }
+}
 
-    pub fn load(&mut self, pack: Bytes) -> Result<CharacterSet, anyhow::Error> {
-        self.0.retain(|w| w.0.strong_count() > 0);
-        let mut zip = ZipArchive::new(Cursor::new(pack))?;
-        let mut set = [const { MaybeUninit::<Arc<Character>>::uninit() }; NUM_CHARS];
-        let mut initialized = 0_usize;
-        if let Err(e) = (|| {
-            for char_idx in rand::seq::index::sample(&mut rng(), zip.len(), zip.len()) {
-                let mut file = zip.by_index(char_idx)?;
-                let Some(mime) = mime_guess::from_path(file.name()).first() else {
-                    continue;
-                };
-                if mime.type_() != "image" {
+/// Reads at most `remaining` bytes from `inner`, failing rather than
+/// stopping quietly once they run out.
+struct Expanded<R> {
+    inner: R,
+    remaining: u64,
+}
+impl<R: Read> Read for Expanded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            return Err(std::io::Error::other("archive is too large"));
+        }
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..len])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// What became of one file in an uploaded pack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryStatus {
+    Accepted,
+    NotImage,
+    /// an image, but in a format we don't accept, like tiff or svg
+    Unsupported,
+    /// the same image as another file in the pack
+    Duplicate,
+    TooLarge,
+    Corrupt,
+    /// in more folders than [`PackLimits`] allows
+    TooDeep,
+    /// the manifest, or a hidden file
+    Ignored,
+    /// not looked at, because enough images had already been found
+    Unused,
+}
+impl EntryStatus {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Accepted => "accepted",
+            Self::NotImage => "not an image",
+            Self::Unsupported => "unsupported format",
+            Self::Duplicate => "duplicate",
+            Self::TooLarge => "too large",
+            Self::Corrupt => "corrupt",
+            Self::TooDeep => "nested too deeply",
+            Self::Ignored => "ignored",
+            Self::Unused => "not needed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PackEntry {
+    pub file: String,
+    pub status: EntryStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// What was found in an uploaded pack, file by file.
+#[derive(Clone, Debug, Serialize)]
+pub struct PackReport {
+    /// every file in the pack, sorted by name
+    pub entries: Vec<PackEntry>,
+    pub usable: usize,
+    pub required: usize,
+}
+impl PackReport {
+    pub fn html(&self) -> String {
+        format!(
+            include_str!("./pack-report.html.template"),
+            usable = self.usable,
+            required = self.required,
+            entries = self
+                .entries
+                .iter()
+                .map(|entry| format!(
+                    "<tr class=\"{}\"><td><code>{}</code></td><td>{}</td><td>{}</td></tr>",
+                    entry.status.label().replace(' ', "-"),
+                    escape_html(&entry.file),
+                    entry.status.label(),
+                    escape_html(entry.reason.as_deref().unwrap_or_default()),
+                ))
+                .collect::<String>(),
+        )
+    }
+}
+
+/// The error for a pack without enough usable images.
+#[derive(Debug)]
+pub struct NotEnoughImages(pub PackReport);
+impl fmt::Display for NotEnoughImages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "found {} usable images in the pack, but {} are needed",
+            self.0.usable, self.0.required
+        )
+    }
+}
+impl std::error::Error for NotEnoughImages {}
+
+/// The error for a pack that couldn't be loaded because its images couldn't
+/// be stored, through no fault of the pack.
+#[derive(Debug)]
+pub struct StoreFailed(pub anyhow::Error);
+impl fmt::Display for StoreFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "couldn't store the pack's images: {}", self.0)
+    }
+}
+impl std::error::Error for StoreFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CharacterSet(pub Vec<Arc<Character>>);
+impl CharacterSet {
+    /// Loads up to `max` randomly chosen characters from a pack, failing
+    /// with [`NotEnoughImages`] if it has fewer than `min` usable images.
+    ///
+    /// Files are recognised as images by their content, whatever they are
+    /// named, and normalized with `images` as they are loaded. Along with the
+    /// characters, returns a report of what became of every file. Packs that
+    /// break `limits` as a whole are rejected outright.
+    pub fn load(
+        store: &BlobStore,
+        images: &ImageConfig,
+        limits: &PackLimits,
+        pack: PackSource,
+        min: usize,
+        max: usize,
+    ) -> Result<(Self, PackReport), anyhow::Error> {
+        let mut files = pack.unpack(limits)?;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut manifest = Manifest::find(&files)?;
+        let mut entries = Vec::with_capacity(files.len());
+        let entry = |file: &String, status, reason: Option<String>| PackEntry {
+            file: file.clone(),
+            status,
+            reason,
+        };
+        // duplicates are found in name order, so which copy of an image is
+        // kept doesn't depend on the random order the rest are tried in
+        let mut candidates = Vec::with_capacity(files.len());
+        let mut seen = HashMap::new();
+        for PackFile { name, data } in &files {
+            if is_ignored(name) {
+                let nested =
+                    is_manifest(name) && !manifest.is_top(name) && !name.starts_with("__MACOSX/");
+                let reason =
+                    nested.then(|| "only a manifest at the top of the pack is read".to_owned());
+                entries.push(entry(name, EntryStatus::Ignored, reason));
+                continue;
+            }
+            if name.split('/').count() > limits.max_depth + 1 {
+                entries.push(entry(
+                    name,
+                    EntryStatus::TooDeep,
+                    Some(format!(
+                        "files can be at most {} folders deep",
+                        limits.max_depth
+                    )),
+                ));
+                continue;
+            }
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    let status = match e {
+                        Rejected::TooLarge(_) => EntryStatus::TooLarge,
+                        _ => EntryStatus::Corrupt,
+                    };
+                    entries.push(entry(name, status, Some(e.to_string())));
                     continue;
                 }
-                if mime.subtype() == "tiff" {
-                    continue;
+            };
+            match seen.entry(Sha256::digest(data)) {
+                Entry::Occupied(original) => {
+                    entries.push(entry(
+                        name,
+                        EntryStatus::Duplicate,
+                        Some(format!("same image as {}", original.get())),
+                    ));
                 }
-                let mut data = BytesMut::zeroed(file.size() as usize);
-                file.read_exact(&mut data)?;
-                let character = Arc::new(Character {
-                    content_type: Some(HeaderValue::from_str(&mime.to_string())?),
-                    data: data.into(),
-                });
-                let weak = WeakHashable(Arc::downgrade(&character));
-                let character = if let Some(c) = self.0.get(&weak).and_then(|c| c.0.upgrade()) {
-                    c
-                } else {
-                    self.0.insert(weak);
-                    character
-                };
-                set[initialized].write(character);
-                initialized += 1;
-                if initialized == set.len() {
-                    break;
+                Entry::Vacant(vacant) => {
+                    vacant.insert(name.clone());
+                    candidates.push((name, data));
                 }
             }
-            if initialized < set.len() {
-                return Err(anyhow!("not enough images in zip file!"));
+        }
+        let mut set = Vec::with_capacity(min);
+        for idx in rand::seq::index::sample(&mut rng(), candidates.len(), candidates.len()) {
+            let (name, data) = candidates[idx];
+            if set.len() == max {
+                entries.push(entry(name, EntryStatus::Unused, None));
+                continue;
             }
-            Ok(())
-        })() {
-            unsafe {
-                for i in 0..initialized {
-                    set[i].assume_init_drop();
-                }
+            let normalized =
+                match images::sniff(data).and_then(|format| images.normalize(data, format)) {
+                    Ok(normalized) => normalized,
+                    Err(e) => {
+                        let status = match e {
+                            Rejected::NotImage(_) => EntryStatus::NotImage,
+                            Rejected::Unsupported(_) => EntryStatus::Unsupported,
+                            Rejected::TooLarge(_) => EntryStatus::TooLarge,
+                            Rejected::Corrupt(_) => EntryStatus::Corrupt,
+                        };
+                        entries.push(entry(name, status, Some(e.to_string())));
+                        continue;
+                    }
+                };
+            entries.push(entry(name, EntryStatus::Accepted, None));
+            let info = manifest.take(name);
+            set.push(Arc::new(Character {
+                image: store.insert(&normalized.image).map_err(StoreFailed)?,
+                thumbnail: store.insert(&normalized.thumbnail).map_err(StoreFailed)?,
+                content_type: Some(HeaderValue::from_static("image/jpeg")),
+                name: info.name,
+                attributes: info.attributes,
+            }));
+        }
+        entries.sort_by(|a, b| a.file.cmp(&b.file));
+        let report = PackReport {
+            entries,
+            usable: set.len(),
+            required: min,
+        };
+        if set.len() < min {
+            return Err(NotEnoughImages(report).into());
+        }
+        Ok((CharacterSet(set), report))
+    }
+
+    /// Picks `count` random characters out of the set.
+    pub fn choose(&self, count: usize) -> Result<Self, anyhow::Error> {
+        if self.0.len() < count {
+            return Err(anyhow!(
+                "pack only has {} characters, but the board needs {count}",
+                self.0.len()
+            ));
+        }
+        Ok(Self(
+            rand::seq::index::sample(&mut rng(), self.0.len(), count)
+                .into_iter()
+                .map(|idx| self.0[idx].clone())
+                .collect(),
+        ))
+    }
+
+    /// Resolves characters saved with [`Character::info`] against the blobs
+    /// already in `store`.
+    pub fn from_info(store: &BlobStore, info: Vec<CharacterInfo>) -> Result<Self, anyhow::Error> {
+        Ok(Self(
+            info.into_iter()
+                .map(|info| Character::from_info(store, info).map(Arc::new))
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+
+    /// Every attribute key used in the set, along with all the values it takes.
+    pub fn attributes(&self) -> BTreeMap<&str, BTreeSet<&str>> {
+        let mut res = BTreeMap::<&str, BTreeSet<&str>>::new();
+        for character in &self.0 {
+            for (key, value) in &character.attributes {
+                res.entry(key).or_default().insert(value);
             }
-            return Err(e);
         }
-        eprintln!("cache has {} items", self.0.len());
-        Ok(CharacterSet(unsafe { std::mem::transmute(set) }))
+        res
     }
 }
 
-pub struct CharacterSet(pub [Arc<Character>; NUM_CHARS]);
+/// Files that go along with a pack's images rather than being characters:
+/// the manifest, and the hidden files some systems add when zipping a folder.
+fn is_ignored(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    is_manifest(path) || name.starts_with('.') || path.starts_with("__MACOSX/")
+}
+
+fn is_manifest(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    matches!(name, "manifest.json" | "manifest.toml")
+}
+
+/// A pack that has been uploaded to the library, and can be used for any
+/// number of games.
+pub struct LibraryPack {
+    pub name: String,
+    pub characters: CharacterSet,
+}
+
+/// Which version of a character's image to serve.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageSize {
+    #[default]
+    Full,
+    Thumbnail,
+}
 
-#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+#[derive(Clone, Debug)]
 pub struct Character {
+    image: Blob,
+    thumbnail: Blob,
     content_type: Option<HeaderValue>,
-    data: Bytes,
+    pub name: Option<String>,
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// A [`Character`] with its image referenced by hash.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CharacterInfo {
+    pub image: String,
+    pub thumbnail: String,
+    pub content_type: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
 }
+
 impl Character {
-    pub fn size(&self) -> usize {
-        self.content_type.as_ref().map_or(0, |h| h.len()) + self.data.len()
+    pub fn from_info(store: &BlobStore, info: CharacterInfo) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            image: store
+                .get(info.image.parse()?)
+                .ok_or_else(|| anyhow!("image {} is missing from the blob store", info.image))?,
+            thumbnail: store.get(info.thumbnail.parse()?).ok_or_else(|| {
+                anyhow!(
+                    "thumbnail {} is missing from the blob store",
+                    info.thumbnail
+                )
+            })?,
+            content_type: info
+                .content_type
+                .map(|c| HeaderValue::from_str(&c))
+                .transpose()?,
+            name: info.name,
+            attributes: info.attributes,
+        })
+    }
+
+    pub fn info(&self) -> CharacterInfo {
+        CharacterInfo {
+            image: self.image.hash().to_string(),
+            thumbnail: self.thumbnail.hash().to_string(),
+            content_type: self
+                .content_type
+                .as_ref()
+                .and_then(|c| c.to_str().ok())
+                .map(|c| c.to_owned()),
+            name: self.name.clone(),
+            attributes: self.attributes.clone(),
+        }
+    }
+
+    /// A short `key: value` summary of the character's attributes.
+    pub fn description(&self) -> String {
+        self.attributes
+            .iter()
+            .map(|(k, v)| format!("{k}: {v}"))
+            .collect::<Vec<_>>()
+            .join(", ")
     }
 
-    pub fn to_response(&self) -> Response<Body> {
+    pub async fn to_response(&self, size: ImageSize) -> Result<Response<Body>, anyhow::Error> {
+        let blob = match size {
+            ImageSize::Full => &self.image,
+            ImageSize::Thumbnail => &self.thumbnail,
+        };
         let mut res = StatusCode::OK.into_response();
-        *res.body_mut() = Body::from(self.data.clone());
+        *res.body_mut() = Body::from(blob.read().await?);
         if let Some(content_type) = self.content_type.clone() {
             res.headers_mut().insert("content-type", content_type);
         }
-        res
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    fn limits() -> PackLimits {
+        PackLimits {
+            max_upload_size: 128 * MIB,
+            max_entries: 10,
+            max_entry_size: 4 * MIB,
+            max_total_size: 8 * MIB,
+            max_ratio: 100,
+            max_depth: 2,
+        }
+    }
+
+    fn zip(files: &[(&str, &[u8])], method: CompressionMethod) -> Bytes {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(
+                *name,
+                SimpleFileOptions::default().compression_method(method),
+            )
+            .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner().into()
+    }
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, name, *data).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Bytes {
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(data).unwrap();
+        gz.finish().unwrap().into()
+    }
+
+    fn rejection(files: &[PackFile]) -> String {
+        match files {
+            [PackFile {
+                data: Err(e @ Rejected::TooLarge(_)),
+                ..
+            }] => e.to_string(),
+            [PackFile { data: Err(e), .. }] => panic!("rejected for the wrong reason: {e}"),
+            _ => panic!("expected a single rejected file"),
+        }
+    }
+
+    fn files(files: &[(&str, &str)]) -> Vec<PackFile> {
+        files
+            .iter()
+            .map(|(name, data)| PackFile {
+                name: name.to_string(),
+                data: Ok(data.as_bytes().to_vec()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn manifest_in_top_folder() {
+        let manifest = r#"{"characters": [{"file": "a/1.png", "name": "One"}]}"#;
+        let files = files(&[
+            ("pack/manifest.json", manifest),
+            ("pack/a/1.png", ""),
+            ("__MACOSX/pack/a/._1.png", ""),
+        ]);
+        let mut manifest = Manifest::find(&files).unwrap();
+        assert_eq!(manifest.take("pack/a/1.png").name.as_deref(), Some("One"));
+    }
+
+    #[test]
+    fn nested_manifest() {
+        let manifest = r#"{"characters": [{"file": "1.png", "name": "One"}]}"#;
+        let files = files(&[
+            ("pack/manifest.json", manifest),
+            ("pack/1.png", ""),
+            ("2.png", ""),
+        ]);
+        let mut manifest = Manifest::find(&files).unwrap();
+        assert!(!manifest.is_top("pack/manifest.json"));
+        assert_eq!(manifest.take("pack/1.png").name, None);
+
+        let manifest = br#"{"characters": []}"#;
+        let pack = zip(
+            &[
+                ("pack/manifest.json", manifest),
+                ("pack/1.png", &png(0)),
+                ("2.png", &png(1)),
+            ],
+            CompressionMethod::Stored,
+        );
+        let (_, report) = load(PackSource::Zip(pack), 2).unwrap();
+        assert_eq!(report.entries[2].file, "pack/manifest.json");
+        assert_eq!(report.entries[2].status, EntryStatus::Ignored);
+        assert_eq!(
+            report.entries[2].reason.as_deref(),
+            Some("only a manifest at the top of the pack is read")
+        );
+    }
+
+    /// A small png, filled with a shade of grey so each one is different.
+    fn png(shade: u8) -> Vec<u8> {
+        let img = image::GrayImage::from_pixel(8, 8, image::Luma([shade]));
+        let mut buf = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png)
+            .unwrap();
+        buf
+    }
+
+    fn load(pack: PackSource, min: usize) -> Result<(CharacterSet, PackReport), anyhow::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlobStore::open(dir.path(), usize::MAX).unwrap();
+        CharacterSet::load(&store, &ImageConfig::default(), &limits(), pack, min, 10)
+    }
+
+    fn statuses(report: &PackReport) -> Vec<(&str, EntryStatus)> {
+        report
+            .entries
+            .iter()
+            .map(|entry| (entry.file.as_str(), entry.status))
+            .collect()
+    }
+
+    fn uploads(files: &[(&str, &[u8])]) -> Vec<(String, Bytes)> {
+        files
+            .iter()
+            .map(|(name, data)| (name.to_string(), Bytes::copy_from_slice(data)))
+            .collect()
+    }
+
+    #[test]
+    fn images_recognised_by_content() {
+        let pack = PackSource::new(uploads(&[
+            ("evil.png", b"<!doctype html><script>alert(1)</script>"),
+            ("logo.png", b"<svg><script>alert(1)</script></svg>"),
+            ("photo", &png(0)),
+            ("scan.png", b"II\x2a\0\x08\0\0\0\0\0\0\0"),
+        ]))
+        .unwrap()
+        .unwrap();
+        let (_, report) = load(pack, 1).unwrap();
+        assert_eq!(
+            statuses(&report),
+            [
+                ("evil.png", EntryStatus::NotImage),
+                ("logo.png", EntryStatus::Unsupported),
+                ("photo", EntryStatus::Accepted),
+                ("scan.png", EntryStatus::Unsupported),
+            ]
+        );
+    }
+
+    /// The names given to the characters that were loaded, sorted.
+    fn names(set: &CharacterSet) -> Vec<&str> {
+        let mut names = set
+            .0
+            .iter()
+            .map(|c| c.name.as_deref().unwrap_or_default())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn tar_pack() {
+        let manifest = br#"{"characters": [{"file": "a.png", "name": "A"}]}"#;
+        let pack = tar(&[
+            ("manifest.json", manifest),
+            ("a.png", &png(0)),
+            ("b.png", &png(1)),
+        ]);
+        let pack = PackSource::new(uploads(&[("pack.tar", &pack)]))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(pack, PackSource::Tar(_)));
+        let (set, report) = load(pack, 2).unwrap();
+        assert_eq!(names(&set), ["", "A"]);
+        assert_eq!(
+            statuses(&report),
+            [
+                ("a.png", EntryStatus::Accepted),
+                ("b.png", EntryStatus::Accepted),
+                ("manifest.json", EntryStatus::Ignored),
+            ]
+        );
+    }
+
+    #[test]
+    fn tar_gz_pack() {
+        let pack = gzip(&tar(&[("pack/a.png", &png(0)), ("pack/b.png", &png(1))]));
+        // named as anything, since archives are recognised by their content
+        let pack = PackSource::new(uploads(&[("pack.bin", &pack)]))
+            .unwrap()
+            .unwrap();
+        assert!(matches!(pack, PackSource::TarGz(_)));
+        let (set, report) = load(pack, 2).unwrap();
+        assert_eq!(set.0.len(), 2);
+        assert_eq!(report.usable, 2);
+    }
+
+    #[test]
+    fn images_on_their_own() {
+        let manifest = br#"{"characters": [{"file": "b.png", "name": "B"}]}"#;
+        // the way a browser uploads a folder of images
+        let pack = PackSource::new(uploads(&[
+            ("folder/a.png", &png(0)),
+            ("folder/b.png", &png(1)),
+            ("folder/manifest.json", manifest),
+            ("folder/.DS_Store", b"\0\0\0\x01Bud1"),
+        ]))
+        .unwrap()
+        .unwrap();
+        assert!(matches!(pack, PackSource::Images(_)));
+        let (set, report) = load(pack, 2).unwrap();
+        assert_eq!(names(&set), ["", "B"]);
+        assert_eq!(
+            statuses(&report),
+            [
+                ("folder/.DS_Store", EntryStatus::Ignored),
+                ("folder/a.png", EntryStatus::Accepted),
+                ("folder/b.png", EntryStatus::Accepted),
+                ("folder/manifest.json", EntryStatus::Ignored),
+            ]
+        );
+    }
+
+    #[test]
+    fn first_copy_kept() {
+        let pack = zip(
+            &[("c.png", &png(0)), ("b.png", &png(1)), ("a.png", &png(0))],
+            CompressionMethod::Stored,
+        );
+        for _ in 0..10 {
+            let (_, report) = load(PackSource::Zip(pack.clone()), 1).unwrap();
+            assert_eq!(report.entries[2].file, "c.png");
+            assert_eq!(report.entries[2].status, EntryStatus::Duplicate);
+            assert_eq!(
+                report.entries[2].reason.as_deref(),
+                Some("same image as a.png")
+            );
+            assert_eq!(report.usable, 2);
+        }
+    }
+
+    #[test]
+    fn archive_mixed_with_images() {
+        let pack = zip(&[("a.png", &png(0))], CompressionMethod::Stored);
+        let err = PackSource::new(uploads(&[("pack.zip", &pack), ("b.png", &png(1))]))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "upload either a single zip, tar or tar.gz file, or images on their own"
+        );
+        assert!(PackSource::new(Vec::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn zip_bomb() {
+        let bomb = vec![0; 2 * MIB as usize];
+        let pack = zip(&[("bomb.png", &bomb)], CompressionMethod::Deflated);
+        let files = PackSource::Zip(pack).unpack(&limits()).unwrap();
+        assert_eq!(
+            rejection(&files),
+            "unzips to more than 100 times its compressed size"
+        );
+    }
+
+    #[test]
+    fn zip_lying_about_size() {
+        let bomb = vec![0; 2 * MIB as usize];
+        let mut pack = zip(&[("bomb.png", &bomb)], CompressionMethod::Deflated).to_vec();
+        // claim the file unzips to 100 bytes, in both the local header and
+        // the central directory
+        pack[22..26].copy_from_slice(&100u32.to_le_bytes());
+        let central = pack.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+        pack[central + 24..central + 28].copy_from_slice(&100u32.to_le_bytes());
+        let files = PackSource::Zip(pack.into()).unpack(&limits()).unwrap();
+        assert_eq!(files[0].name, "bomb.png");
+        assert_eq!(
+            rejection(&files),
+            "unzips to more than 100 times its compressed size"
+        );
+    }
+
+    #[test]
+    fn too_many_files() {
+        let names = (0..11).map(|i| format!("{i}.png")).collect::<Vec<_>>();
+        let files = names
+            .iter()
+            .map(|name| (name.as_str(), &b"x"[..]))
+            .collect::<Vec<_>>();
+        let err = PackSource::Zip(zip(&files, CompressionMethod::Stored))
+            .unpack(&limits())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "packs can have at most 10 files");
+        let err = PackSource::Tar(tar(&files).into())
+            .unpack(&limits())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "packs can have at most 10 files");
+    }
+
+    #[test]
+    fn too_deep() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlobStore::open(dir.path(), usize::MAX).unwrap();
+        let pack = zip(
+            &[("a/b/c/deep.png", b"x"), ("a/b/shallow.png", b"x")],
+            CompressionMethod::Stored,
+        );
+        let err = CharacterSet::load(
+            &store,
+            &ImageConfig::default(),
+            &limits(),
+            PackSource::Zip(pack),
+            1,
+            1,
+        )
+        .err()
+        .unwrap();
+        let report = &err.downcast_ref::<NotEnoughImages>().unwrap().0;
+        assert_eq!(report.entries[0].file, "a/b/c/deep.png");
+        assert!(matches!(report.entries[0].status, EntryStatus::TooDeep));
+        assert_eq!(
+            report.entries[0].reason.as_deref(),
+            Some("files can be at most 2 folders deep")
+        );
+        assert!(!matches!(report.entries[1].status, EntryStatus::TooDeep));
+    }
+
+    #[test]
+    fn tar_gz_bomb() {
+        let bomb = vec![0; 3 * MIB as usize];
+        let pack = gzip(&tar(&[("bomb.png", &bomb)]));
+        let err = PackSource::TarGz(pack).unpack(&limits()).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the tar.gz file unpacks to more than 100 times its size"
+        );
+    }
+
+    #[test]
+    fn seven_zip() {
+        let upload = Bytes::from_static(b"7z\xbc\xaf\x27\x1c\x00\x04");
+        let err = PackSource::new(vec![("pack.7z".to_owned(), upload)])
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "pack.7z is a 7z file, which isn't supported: upload a zip, tar or tar.gz file instead"
+        );
+    }
+
+    #[test]
+    fn too_large_in_total() {
+        let file = vec![0; 3 * MIB as usize];
+        let pack = zip(
+            &[("0.png", &file), ("1.png", &file), ("2.png", &file)],
+            CompressionMethod::Stored,
+        );
+        let err = PackSource::Zip(pack).unpack(&limits()).err().unwrap();
+        assert_eq!(err.to_string(), "the pack unpacks to more than 8 MiB");
     }
 }
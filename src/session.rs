@@ -0,0 +1,202 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{FromRequestParts, RawPathParams};
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::utils::SyncMutex;
+use crate::{AppState, GameState};
+
+const COOKIE_NAME: &str = "session";
+
+/// Signs session tokens, so knowing a player's id isn't enough to act as
+/// them.
+#[derive(Clone)]
+pub struct SessionKey(Vec<u8>);
+impl SessionKey {
+    /// Uses `IMPOSTER_ROSTER_SECRET` if it is set. Otherwise the secret is
+    /// kept in `data_dir` so sessions survive a restart, or made up on the
+    /// spot if there is nowhere to keep it.
+    pub fn load(data_dir: Option<&Path>) -> Result<Self, anyhow::Error> {
+        if let Some(secret) = std::env::var_os("IMPOSTER_ROSTER_SECRET") {
+            return Ok(Self(secret.into_encoded_bytes()));
+        }
+        let Some(data_dir) = data_dir else {
+            return Ok(Self::random());
+        };
+        let path = data_dir.join("secret");
+        match fs::read(&path) {
+            Ok(secret) => Ok(Self(secret)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = Self::random();
+                fs::create_dir_all(data_dir)?;
+                let tmp = path.with_extension("tmp");
+                let mut file = fs::File::create(&tmp)?;
+                file.write_all(&key.0)?;
+                file.sync_all()?;
+                fs::rename(tmp, path)?;
+                Ok(key)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn random() -> Self {
+        Self(rand::random::<[u8; 32]>().to_vec())
+    }
+
+    fn mac(&self, uid: u64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("hmac accepts keys of any length");
+        mac.update(&uid.to_be_bytes());
+        mac
+    }
+
+    /// Makes a token for `uid`, of the form `<uid>.<signature>`.
+    pub fn sign(&self, uid: u64) -> String {
+        let signature = self
+            .mac(uid)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        format!("{uid}.{signature}")
+    }
+
+    /// Checks a token made by [`SessionKey::sign`], returning the user id it
+    /// was made for.
+    pub fn verify(&self, token: &str) -> Option<u64> {
+        let (uid, signature) = token.split_once('.')?;
+        let uid = uid.parse().ok()?;
+        if signature.len() % 2 != 0 || !signature.is_ascii() {
+            return None;
+        }
+        let signature = (0..signature.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&signature[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        self.mac(uid).verify_slice(&signature).ok()?;
+        Some(uid)
+    }
+
+    /// A `set-cookie` header that signs the browser in as `uid`. It's only
+    /// marked `Secure` when the page was served over https, since browsers
+    /// would otherwise drop it on a plain http LAN game.
+    pub fn cookie(&self, uid: u64, secure: bool) -> HeaderValue {
+        HeaderValue::from_str(&format!(
+            "{COOKIE_NAME}={}; Path=/; HttpOnly;{} SameSite=Strict",
+            self.sign(uid),
+            if secure { " Secure;" } else { "" },
+        ))
+        .expect("session tokens are valid header values")
+    }
+}
+
+fn app(parts: &Parts) -> &Arc<SyncMutex<AppState>> {
+    parts
+        .extensions
+        .get::<Arc<SyncMutex<AppState>>>()
+        .expect("app state is added to every request")
+}
+
+/// A user id from a verified session, sent either as the session cookie or,
+/// for the api, as an `Authorization: Bearer` token.
+pub struct Session(pub u64);
+impl<S: Send + Sync> FromRequestParts<S> for Session {
+    type Rejection = SessionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let key = app(parts).peek(|a| a.session_key.clone());
+        let bearer = parts
+            .headers
+            .get("authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+        let cookie = parts
+            .headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|c| c.to_str().ok())
+            .flat_map(|c| c.split(';'))
+            .filter_map(|c| c.trim().strip_prefix(COOKIE_NAME)?.strip_prefix('='));
+        bearer
+            .into_iter()
+            .chain(cookie)
+            .find_map(|token| key.verify(token.trim()))
+            .map(Session)
+            .ok_or(SessionRejection::Unauthorized)
+    }
+}
+
+/// A player in the game named by the `game_id` path parameter, identified by
+/// their session. Extracting this claims the player's seat.
+pub struct Player {
+    pub game_id: u64,
+    pub game: Arc<SyncMutex<GameState>>,
+    pub id: u64,
+}
+impl<S: Send + Sync> FromRequestParts<S> for Player {
+    type Rejection = SessionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let game_id = RawPathParams::from_request_parts(parts, state)
+            .await
+            .ok()
+            .and_then(|params| {
+                params
+                    .iter()
+                    .find(|(name, _)| *name == "game_id")
+                    .and_then(|(_, id)| id.parse::<u64>().ok())
+            })
+            .ok_or(SessionRejection::NotFound)?;
+        let game = app(parts)
+            .peek(|a| a.games.get(&game_id).and_then(|g| g.get()))
+            .ok_or(SessionRejection::NotFound)?;
+        let Session(id) = Session::from_request_parts(parts, state).await?;
+        if !game.mutate(|g| g.claim(id)) {
+            return Err(SessionRejection::Unauthorized);
+        }
+        Ok(Player { game_id, game, id })
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionRejection {
+    NotFound,
+    Unauthorized,
+}
+impl SessionRejection {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+        }
+    }
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::NotFound => "game not found",
+            Self::Unauthorized => "a player session is required for this game",
+        }
+    }
+}
+impl IntoResponse for SessionRejection {
+    fn into_response(self) -> Response {
+        let mut res = self.status().into_response();
+        *res.body_mut() = Body::from(match self {
+            Self::NotFound => include_str!("./not_found.html"),
+            Self::Unauthorized => include_str!("./unauthorized.html"),
+        });
+        res.headers_mut()
+            .insert("content-type", HeaderValue::from_static("text/html"));
+        res
+    }
+}
@@ -0,0 +1,111 @@
+// spectators can't mark tiles or guess
+function handle_click(id) {}
+
+function seat_label(seat) {
+  return seat === null ? 'Someone' : `Player ${seat + 1}`
+}
+
+function log(seat, html) {
+  // reuse the player colours, alternating between seats
+  const cls = seat % 2 === 1 ? 'theirs' : 'mine'
+  eventLog.innerHTML += `<p class="${cls}">${html}</p>`
+}
+
+/**
+ * Catches up with a snapshot of the game, sent when the events we missed are
+ * no longer available.
+ */
+function sync(snapshot) {
+  if (snapshot.round !== round) {
+    // the board has been reshuffled since the page loaded
+    window.location.reload()
+    return
+  }
+  seq = snapshot.seq
+  if (snapshot.turn !== null) {
+    document.getElementById('turn-status').innerHTML =
+      `${seat_label(snapshot.turn)}'s turn`
+  }
+  document.getElementById('spectator-count').innerHTML = snapshot.spectators
+  if (snapshot.game_over) show_results()
+}
+
+/**
+ * Switches to showing the tiles the player in `seat` has ruled out.
+ */
+function follow(seat) {
+  const params = new URLSearchParams({ key: spectator_key })
+  if (seat !== '') params.set('seat', seat)
+  window.location.search = params.toString()
+}
+
+function show_results() {
+  fetch(`./results?key=${spectator_key}`).then(async (res) => {
+    if (res.status === 200) {
+      document.getElementById('results').innerHTML = await res.text()
+    } else {
+      console.error(res)
+    }
+  })
+}
+
+function load() {
+  eventLog = document.getElementById('event-log')
+
+  connect()
+}
+
+/**
+ * Opens the websocket, picking up after the last event we saw.
+ */
+function connect() {
+  open_events(
+    `./watch-ws?key=${spectator_key}&since=${seq}`,
+    connect,
+    handle_event,
+  )
+}
+
+function handle_event(event) {
+  const player = seat_label(event.seat)
+  switch (event.type) {
+    case 'connected': {
+      log(event.seat, `<b class="title">${player} has connected.</b>`)
+      break
+    }
+    case 'disconnected': {
+      log(event.seat, `<b class="title">${player} has disconnected.</b>`)
+      break
+    }
+    case 'restart': {
+      window.location.reload()
+      break
+    }
+    case 'turn': {
+      document.getElementById('turn-status').innerHTML = `${player}'s turn`
+      break
+    }
+    case 'game-over': {
+      show_results()
+      break
+    }
+    case 'eliminate': {
+      if (event.seat === following) {
+        for (const [row, col] of event.tiles) {
+          document
+            .getElementById(`idx-${row}_${col}`)
+            .classList.toggle('blackout', event.eliminated)
+        }
+      }
+      break
+    }
+    case 'spectators': {
+      document.getElementById('spectator-count').innerHTML = event.count
+      break
+    }
+    case 'react': {
+      add_reaction(event.message, event.emoji)
+      break
+    }
+  }
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::blobs::BlobStore;
+use crate::pack::{CharacterInfo, CharacterSet, LibraryPack};
+use crate::GameState;
+
+/// A point-in-time copy of a game along with when it expires.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GameSnapshot {
+    pub expires_at: SystemTime,
+    pub game: GameState,
+}
+
+/// Somewhere games and library packs can be written so they survive a restart.
+pub trait Storage: Send + Sync {
+    fn save(&self, id: u64, snapshot: &GameSnapshot) -> Result<(), anyhow::Error>;
+    fn remove(&self, id: u64) -> Result<(), anyhow::Error>;
+    fn ids(&self) -> Result<Vec<u64>, anyhow::Error>;
+    /// Loads a saved game, looking up its images in `blobs`.
+    fn load(&self, id: u64, blobs: &BlobStore) -> Result<GameSnapshot, anyhow::Error>;
+    fn save_pack(&self, id: u64, pack: &LibraryPack) -> Result<(), anyhow::Error>;
+    /// Loads every pack in the library, looking up their images in `blobs`.
+    /// Packs that can't be loaded are logged and left out.
+    fn load_packs(&self, blobs: &BlobStore) -> Result<Vec<(u64, LibraryPack)>, anyhow::Error>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredPack {
+    name: String,
+    characters: Vec<CharacterInfo>,
+}
+
+/// Stores each game as a directory of json files, and each library pack as a
+/// single json file:
+///
+/// ```text
+/// <root>/games/<id>/state.json
+/// <root>/games/<id>/characters.json
+/// <root>/packs/<id>.json
+/// ```
+///
+/// Images are referenced by hash, and live in the [`BlobStore`].
+pub struct DirStorage {
+    games: PathBuf,
+    packs: PathBuf,
+}
+impl DirStorage {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let games = root.as_ref().join("games");
+        fs::create_dir_all(&games)?;
+        let packs = root.as_ref().join("packs");
+        fs::create_dir_all(&packs)?;
+        Ok(Self { games, packs })
+    }
+
+    fn game_dir(&self, id: u64) -> PathBuf {
+        self.games.join(id.to_string())
+    }
+}
+impl Storage for DirStorage {
+    fn save(&self, id: u64, snapshot: &GameSnapshot) -> Result<(), anyhow::Error> {
+        let dir = self.game_dir(id);
+        fs::create_dir_all(&dir)?;
+        let characters = &snapshot.game.characters.0;
+        write_atomic(
+            &dir.join("characters.json"),
+            &serde_json::to_vec(&characters.iter().map(|c| c.info()).collect::<Vec<_>>())?,
+        )?;
+        write_atomic(&dir.join("state.json"), &serde_json::to_vec(snapshot)?)
+    }
+
+    fn remove(&self, id: u64) -> Result<(), anyhow::Error> {
+        let dir = self.game_dir(id);
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    fn ids(&self) -> Result<Vec<u64>, anyhow::Error> {
+        let mut res = Vec::new();
+        for entry in fs::read_dir(&self.games)? {
+            if let Some(id) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) {
+                res.push(id);
+            }
+        }
+        Ok(res)
+    }
+
+    fn load(&self, id: u64, blobs: &BlobStore) -> Result<GameSnapshot, anyhow::Error> {
+        let dir = self.game_dir(id);
+        let mut snapshot: GameSnapshot =
+            serde_json::from_slice(&fs::read(dir.join("state.json"))?)?;
+        let characters: Vec<CharacterInfo> =
+            serde_json::from_slice(&fs::read(dir.join("characters.json"))?)?;
+        if characters.len() != snapshot.game.rows * snapshot.game.cols {
+            return Err(anyhow!("game {id} has the wrong number of characters"));
+        }
+        snapshot.game.characters = CharacterSet::from_info(blobs, characters)?;
+        Ok(snapshot)
+    }
+
+    fn save_pack(&self, id: u64, pack: &LibraryPack) -> Result<(), anyhow::Error> {
+        write_atomic(
+            &self.packs.join(format!("{id}.json")),
+            &serde_json::to_vec(&StoredPack {
+                name: pack.name.clone(),
+                characters: pack.characters.0.iter().map(|c| c.info()).collect(),
+            })?,
+        )
+    }
+
+    fn load_packs(&self, blobs: &BlobStore) -> Result<Vec<(u64, LibraryPack)>, anyhow::Error> {
+        let mut res = Vec::new();
+        for entry in fs::read_dir(&self.packs)? {
+            let path = entry?.path();
+            let Some(id) = path
+                .file_name()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.strip_suffix(".json"))
+                .and_then(|s| s.parse().ok())
+            else {
+                continue;
+            };
+            let load = || {
+                let pack: StoredPack = serde_json::from_slice(&fs::read(&path)?)?;
+                Ok::<_, anyhow::Error>(LibraryPack {
+                    name: pack.name,
+                    characters: CharacterSet::from_info(blobs, pack.characters)?,
+                })
+            };
+            // one bad pack shouldn't keep the rest of the library from loading
+            match load() {
+                Ok(pack) => res.push((id, pack)),
+                Err(e) => {
+                    eprintln!("failed to load pack {id}: {e}");
+                    eprintln!("{e:?}");
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+    // unique, so two writes to the same file can't mix their data up
+    let tmp = path.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
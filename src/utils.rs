@@ -1,10 +1,12 @@
 use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
 use std::sync::{Arc, Weak};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+use anyhow::anyhow;
 use serde::de::Visitor;
-use serde::Deserializer;
+use serde::{Deserializer, Serializer};
 use tokio::task::{JoinError, JoinHandle};
 
 #[derive(Debug, Default)]
@@ -23,12 +25,6 @@ impl<T> SyncMutex<T> {
 
 #[pin_project::pin_project(PinnedDrop)]
 pub struct NonDetachingJoinHandle<T>(#[pin] JoinHandle<T>);
-impl<T> NonDetachingJoinHandle<T> {
-    pub async fn wait_for_abort(self) -> Result<T, JoinError> {
-        self.abort();
-        self.await
-    }
-}
 impl<T> From<JoinHandle<T>> for NonDetachingJoinHandle<T> {
     fn from(t: JoinHandle<T>) -> Self {
         NonDetachingJoinHandle(t)
@@ -67,6 +63,7 @@ impl<T> Future for NonDetachingJoinHandle<T> {
 pub struct TimedResource<T: 'static + Send + Sync> {
     handle: NonDetachingJoinHandle<()>,
     resource: Weak<T>,
+    expires_at: SystemTime,
 }
 impl<T: 'static + Send + Sync> TimedResource<T> {
     pub fn new(resource: T, timer: Duration) -> Self {
@@ -79,9 +76,21 @@ impl<T: 'static + Send + Sync> TimedResource<T> {
         Self {
             handle: handle.into(),
             resource: weak,
+            expires_at: SystemTime::now() + timer,
         }
     }
 
+    /// Restores a resource that was created with [`TimedResource::new`] before
+    /// a restart, keeping its original expiry time.
+    pub fn until(resource: T, expires_at: SystemTime) -> Self {
+        let timer = expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        let mut res = Self::new(resource, timer);
+        res.expires_at = expires_at;
+        res
+    }
+
     pub fn get(&self) -> Option<Arc<T>> {
         self.resource.upgrade()
     }
@@ -89,6 +98,10 @@ impl<T: 'static + Send + Sync> TimedResource<T> {
     pub fn is_timed_out(&self) -> bool {
         self.handle.is_finished()
     }
+
+    pub fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
 }
 
 pub fn deserialize_bigint<'de, D>(deserializer: D) -> Result<u64, D::Error>
@@ -124,3 +137,62 @@ where
 
     deserializer.deserialize_any(MyVisitor)
 }
+
+/// Serializes a u64 as a string, since javascript numbers can't represent it
+pub fn serialize_bigint<S>(val: &u64, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(val)
+}
+
+/// Reads the setting in environment variable `name`, or `None` if it isn't
+/// set. A value that can't be parsed is an error naming the variable.
+pub fn env_var<T>(name: &str) -> Result<Option<T>, anyhow::Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(val) => val
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("invalid {name} {val:?}: {e}")),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(anyhow!("invalid {name}: {e}")),
+    }
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => res.push_str("&amp;"),
+            '<' => res.push_str("&lt;"),
+            '>' => res.push_str("&gt;"),
+            '"' => res.push_str("&quot;"),
+            '\'' => res.push_str("&#39;"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// Strips the tags out of html, for plain text exports.
+pub fn html_to_text(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut in_tag = false;
+    for c in s.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            c if !in_tag => res.push(c),
+            _ => (),
+        }
+    }
+    res.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
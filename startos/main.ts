@@ -27,7 +27,8 @@ export const main = sdk.setupMain(async ({ effects, started }) => {
   return sdk.Daemons.of(effects, started, healthReceipts).addDaemon('primary', {
     subcontainer: { imageId: 'imposter-roster' },
     command: ['imposter-roster'],
-    mounts: sdk.Mounts.of(),
+    env: { IMPOSTER_ROSTER_DATA_DIR: '/data' },
+    mounts: sdk.Mounts.of().addVolume('main', null, '/data', false),
     ready: {
       display: 'Web Interface',
       fn: () =>